@@ -0,0 +1,122 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use serde_json;
+use std::collections::BTreeMap;
+
+use error;
+use object::ObjectFile;
+
+/// Written in every archive so that other JSON isn't mistaken for one
+pub const ARCHIVE_FORMAT: &str = "hassel_asm archive";
+/// Bumped whenever the archive layout changes
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Object file in an archive, named after the file that it was added from
+#[derive(Debug, Serialize, Deserialize, new)]
+pub struct ArchiveMember {
+    pub name: String,
+    pub object: ObjectFile,
+}
+
+/// Bundles object files with an index of the names that they export. Linking an archive only pulls in the members
+/// that export names which the rest of the program imports, like a static library.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    format: String,
+    version: u32,
+    /// Member that exports each name
+    index: BTreeMap<String, usize>,
+    members: Vec<ArchiveMember>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    format: String,
+}
+
+impl Default for Archive {
+    fn default() -> Archive {
+        Archive {
+            format: ARCHIVE_FORMAT.into(),
+            version: ARCHIVE_VERSION,
+            index: BTreeMap::new(),
+            members: Vec::new(),
+        }
+    }
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    /// True if the JSON is an archive rather than an object file
+    pub fn is_archive(json: &str) -> bool {
+        serde_json::from_str::<Header>(json)
+            .map(|header| header.format == ARCHIVE_FORMAT)
+            .unwrap_or(false)
+    }
+
+    pub fn from_json(json: &str) -> error::Result<Archive> {
+        let archive: Archive = serde_json::from_str(json)?;
+        if archive.format != ARCHIVE_FORMAT {
+            bail!(error::ErrorKind::InvalidArchive(format!(
+                "expected format \"{}\", but found \"{}\"",
+                ARCHIVE_FORMAT, archive.format
+            )));
+        }
+        if archive.version != ARCHIVE_VERSION {
+            bail!(error::ErrorKind::InvalidArchive(format!(
+                "expected version {}, but found version {}",
+                ARCHIVE_VERSION, archive.version
+            )));
+        }
+        for member in &archive.members {
+            member.object.check_version()?;
+        }
+        Ok(archive)
+    }
+
+    pub fn to_json(&self) -> error::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Adds an object and indexes its exports, which can't already be exported by another member
+    pub fn add_member<N: Into<String>>(&mut self, name: N, object: ObjectFile) -> error::Result<()> {
+        let name = name.into();
+        for export in object.exports() {
+            if let Some(&index) = self.index.get(&**export) {
+                bail!(error::ErrorKind::InvalidArchive(format!(
+                    "\"{}\" is exported by both {} and {}",
+                    export, self.members[index].name, name
+                )));
+            }
+        }
+        let index = self.members.len();
+        for export in object.exports() {
+            self.index.insert((**export).clone(), index);
+        }
+        self.members.push(ArchiveMember::new(name, object));
+        Ok(())
+    }
+
+    pub fn members(&self) -> &[ArchiveMember] {
+        &self.members
+    }
+
+    /// Returns the exported names with the index of the member that exports each, in name order
+    pub fn index(&self) -> &BTreeMap<String, usize> {
+        &self.index
+    }
+
+    pub fn into_members(self) -> Vec<ArchiveMember> {
+        self.members
+    }
+}
@@ -9,27 +9,45 @@
 
 use serde_json;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 
 use ast;
+use diagnostic::{self, Diagnostic, DiagnosticCode};
 use ir;
 use ir::gen::AppendBytes;
 use ir::map::SourceMap;
-use error;
+use error::{self, Warning, WarningConfig};
+use format::OutputFormat;
+use format::ines::InesOptions;
+use layout::MemoryLayout;
+use object;
+use src_tag::SrcTag;
 use src_unit::SrcUnits;
+use target::{Cpu, Target};
 
 #[derive(Debug, new)]
 pub struct AssemblerOutput {
     pub ast: Option<Vec<ast::Statement>>,
     pub ir: Option<ir::IR>,
+    /// Program in the target's output format
     pub bytes: Option<Vec<u8>>,
     pub source_map: Option<String>,
+    /// Labels and constants listed by scope
+    pub symbols: Option<String>,
+    /// Removable code that the linker left out, one line each
+    pub removed: Option<String>,
+    /// Warnings reported during a successful assembly
+    pub diagnostics: Vec<Diagnostic>,
+    pub src_units: SrcUnits,
 }
 
 #[derive(Default)]
 pub struct Assembler {
     src_units: SrcUnits,
     units: Vec<ast::Statement>,
+    target: Target,
+    warnings: WarningConfig,
 }
 
 impl Assembler {
@@ -37,19 +55,89 @@ impl Assembler {
         Assembler::default()
     }
 
-    fn parse_units(&mut self, unit_name: &str, unit: &str) -> error::Result<Vec<ast::Statement>> {
+    /// Sets how many columns a tab advances to in reported locations
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.src_units.set_tab_width(tab_width);
+    }
+
+    pub fn set_cpu(&mut self, cpu: Cpu) {
+        self.target.cpu = cpu;
+    }
+
+    /// Sets the byte used for gaps in the output and for `.pad` without a fill value; EPROMs usually want `$FF`
+    pub fn set_fill_byte(&mut self, fill_byte: u8) {
+        self.target.fill_byte = fill_byte;
+    }
+
+    /// Places code by segment into the layout's memory areas rather than by `.org`
+    pub fn set_layout(&mut self, layout: MemoryLayout) -> error::Result<()> {
+        layout.validate()?;
+        self.target.layout = Some(layout);
+        Ok(())
+    }
+
+    /// Sets the file format that the output's bytes are written in
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.target.format = format;
+    }
+
+    /// Sets iNES header fields, which take the place of those that the program gives
+    pub fn set_ines_options(&mut self, options: InesOptions) {
+        self.target.ines = options;
+    }
+
+    pub fn set_warning(&mut self, warning: Warning, enabled: bool) {
+        self.warnings.set_enabled(warning, enabled);
+    }
+
+    pub fn set_warnings_as_errors(&mut self, warnings_as_errors: bool) {
+        self.warnings.set_warnings_as_errors(warnings_as_errors);
+    }
+
+    fn parse_units(&mut self, unit_name: &str, unit: &str, diagnostics: &mut Vec<Diagnostic>) -> Vec<ast::Statement> {
         let unit_id = self.src_units.push_unit(unit_name.into(), unit.into());
-        let parsed = ast::Statement::parse(self.src_units.unit(unit_id))?;
+        let parsed = match ast::Statement::parse(self.src_units.unit(unit_id)) {
+            Ok(parsed) => parsed,
+            Err(errors) => {
+                diagnostics.extend(errors.into_iter());
+                return Vec::new();
+            }
+        };
         let mut units = Vec::with_capacity(parsed.len());
 
         for statement in parsed.into_iter() {
             match statement {
-                ast::Statement::MetaInstruction(ast::MetaInstruction::Include(_tag, ref file_name)) => {
-                    let mut file = File::open(&**file_name)?;
-                    let mut contents = String::new();
-                    file.read_to_string(&mut contents)?;
-                    let included_units = self.parse_units(file_name, &contents)?;
-                    units.extend(included_units.into_iter());
+                ast::Statement::MetaInstruction(ast::MetaInstruction::Include(tag, ref file_name)) => {
+                    match Assembler::read_file(file_name) {
+                        Ok(contents) => {
+                            let included_units = self.parse_units(file_name, &contents, diagnostics);
+                            units.extend(included_units.into_iter());
+                        }
+                        Err(err) => diagnostics.push(
+                            Diagnostic::error(
+                                DiagnosticCode::IncludeFailed,
+                                format!("failed to include \"{}\": {}", file_name, err),
+                            ).with_span(tag),
+                        ),
+                    }
+                }
+                ast::Statement::MetaInstruction(ast::MetaInstruction::IncBin(tag, ref file_name)) => {
+                    match Assembler::read_binary_file(file_name) {
+                        Ok(ref contents) if contents.is_empty() => {}
+                        Ok(contents) => {
+                            let bytes = contents
+                                .into_iter()
+                                .map(|byte| ast::Expr::Number(tag, ast::Number::Byte(byte)))
+                                .collect();
+                            units.push(ast::Statement::MetaInstruction(ast::MetaInstruction::Byte(tag, bytes)));
+                        }
+                        Err(err) => diagnostics.push(
+                            Diagnostic::error(
+                                DiagnosticCode::IncludeFailed,
+                                format!("failed to include \"{}\": {}", file_name, err),
+                            ).with_span(tag),
+                        ),
+                    }
                 }
                 _ => {
                     units.push(statement);
@@ -57,58 +145,211 @@ impl Assembler {
             }
         }
 
-        Ok(units)
+        units
+    }
+
+    fn read_file(file_name: &str) -> io::Result<String> {
+        let mut file = File::open(file_name)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn read_binary_file(file_name: &str) -> io::Result<Vec<u8>> {
+        let mut file = File::open(file_name)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(contents)
     }
 
     pub fn parse_unit(&mut self, unit_name: &str, unit: &str) -> error::Result<()> {
-        let units = self.parse_units(unit_name, unit)?;
+        let mut diagnostics = Vec::new();
+        let units = self.parse_units(unit_name, unit, &mut diagnostics);
+        if diagnostic::has_errors(&diagnostics) {
+            return Err(error::ErrorKind::Diagnostics(self.src_units.clone(), diagnostics).into());
+        }
         self.units.extend(units.into_iter());
         Ok(())
     }
 
     pub fn assemble(self) -> error::Result<AssemblerOutput> {
+        let (ir, diagnostics) = match ir::gen::IRGenerator::generate(&self.units, &self.target, &self.warnings) {
+            Ok(result) => result,
+            Err(diagnostics) => return Err(error::ErrorKind::Diagnostics(self.src_units, diagnostics).into()),
+        };
+        let mut output = Assembler::build_output(ir, diagnostics, self.src_units, &self.target, &self.warnings)?;
+        output.ast = Some(self.units);
+        Ok(output)
+    }
+
+    /// Assembles the parsed units into an object file, leaving placement and symbol resolution to the linker
+    pub fn compile(self) -> error::Result<object::ObjectFile> {
+        match ir::gen::IRGenerator::generate_unresolved(&self.units, &self.target, &self.warnings) {
+            Ok((ir, diagnostics)) => Ok(object::ObjectFile::new(&self.src_units, ir, diagnostics)),
+            Err(diagnostics) => Err(error::ErrorKind::Diagnostics(self.src_units, diagnostics).into()),
+        }
+    }
+
+    /// Builds the source map, symbol listing, and bytes in the target's format for resolved IR
+    pub(crate) fn build_output(
+        ir: ir::IR,
+        diagnostics: Vec<Diagnostic>,
+        src_units: SrcUnits,
+        target: &Target,
+        warnings: &WarningConfig,
+    ) -> error::Result<AssemblerOutput> {
         let mut output = AssemblerOutput {
-            ast: Some(self.units),
+            ast: None,
             ir: None,
             bytes: None,
             source_map: None,
+            symbols: None,
+            removed: None,
+            diagnostics: diagnostics,
+            src_units: SrcUnits::new(),
         };
 
-        output.ir = Some(Assembler::translate_error(
-            &self.src_units,
-            ir::gen::IRGenerator::generate(output.ast.as_ref().unwrap()),
-        )?);
+        output.source_map = Some(serde_json::to_string(&SourceMap::new(&src_units, &ir))?);
+        output.symbols = Some(ir::listing::symbol_listing(&ir));
 
-        let src_map = SourceMap::new(&self.src_units, output.ir.as_ref().unwrap());
-        output.source_map = Some(serde_json::to_string(&src_map)?);
+        let mut byte_diagnostics = Vec::new();
+        let bytes = match target.layout {
+            Some(ref layout) => Assembler::convert_areas_to_bytes(&ir, layout),
+            None => Assembler::convert_to_bytes(&ir, target.fill_byte, &mut byte_diagnostics),
+        };
+        output
+            .diagnostics
+            .extend(warnings.apply(byte_diagnostics).into_iter());
+        if diagnostic::has_errors(&output.diagnostics) {
+            return Err(error::ErrorKind::Diagnostics(src_units, output.diagnostics).into());
+        }
+        output.bytes = Some(target.format.write(&ir, target, bytes)?);
 
-        output.bytes = Some(Assembler::convert_to_bytes(output.ir.as_ref().unwrap()));
+        output.ir = Some(ir);
+        output.src_units = src_units;
         Ok(output)
     }
 
-    fn translate_error<T>(src_units: &SrcUnits, result: error::Result<T>) -> error::Result<T> {
-        match result {
-            Ok(_) => result,
-            Err(err) => Err(error::ErrorKind::SrcUnitError(error::format_error(src_units, &err)).into()),
-        }
-    }
+    /// Lays the blocks out into a flat image that starts at the first block's load position
+    fn convert_to_bytes(ir: &ir::IR, fill_byte: u8, diagnostics: &mut Vec<Diagnostic>) -> Vec<u8> {
+        let mut runs: Vec<Run> = Vec::new();
+        let mut start_pos = None;
+        let mut bytes = Vec::new();
+        let outputs = ir.block_outputs();
+        for (block, output) in ir.blocks.iter().zip(outputs) {
+            let position = match block.load_position {
+                Some(position) => position as usize,
+                None => {
+                    diagnostics.push(
+                        Diagnostic::error(DiagnosticCode::UnplacedBlock, "block was never assigned an address")
+                            .with_span(block.tag),
+                    );
+                    continue;
+                }
+            };
+            if !output {
+                continue;
+            }
+            let start = *start_pos.get_or_insert(position);
+            let current_pos = start + bytes.len();
+
+            let continues_run = runs.last().map(|run| run.end == position).unwrap_or(false);
+            if continues_run {
+                runs.last_mut().unwrap().end += block.length as usize;
+            } else {
+                runs.push(Run {
+                    tag: block.tag,
+                    start: position,
+                    end: position + block.length as usize,
+                    output_position: if position < current_pos {
+                        Some(current_pos)
+                    } else {
+                        None
+                    },
+                });
+            }
 
-    fn convert_to_bytes(ir: &ir::IR) -> Vec<u8> {
-        if ir.blocks.is_empty() {
-            return Vec::new();
+            if position > current_pos {
+                bytes.resize(position - start, fill_byte);
+            }
+            block.append_bytes(&mut bytes);
         }
 
-        let start_pos = ir.blocks[0].position.unwrap() as usize;
+        Assembler::check_runs(&runs, diagnostics);
+        bytes
+    }
 
+    /// Outputs each output area in full, in the order the layout lists them. Overflowing areas are reported
+    /// while resolving, so every block fits in its area here.
+    fn convert_areas_to_bytes(ir: &ir::IR, layout: &MemoryLayout) -> Vec<u8> {
         let mut bytes = Vec::new();
-        for block in &ir.blocks {
-            let current_pos = start_pos + bytes.len();
-            assert!(current_pos <= 0xFFFF && current_pos + block.length as usize <= 0xFFFF);
-            if block.position.unwrap() as usize > current_pos {
-                bytes.resize(block.position.unwrap() as usize - start_pos, 0);
+        for (index, area) in layout.areas.iter().enumerate() {
+            if !area.output {
+                continue;
             }
-            block.append_bytes(&mut bytes);
+            let mut area_bytes = vec![area.fill; area.size as usize];
+            for block in &ir.blocks {
+                if layout.segment_area(&block.segment) != Some(index) {
+                    continue;
+                }
+                let mut block_bytes = Vec::new();
+                block.append_bytes(&mut block_bytes);
+                let offset = (block.load_position.unwrap() - area.start) as usize;
+                area_bytes[offset..offset + block_bytes.len()].copy_from_slice(&block_bytes);
+            }
+            bytes.extend(area_bytes);
         }
         bytes
     }
+
+    fn check_runs(runs: &[Run], diagnostics: &mut Vec<Diagnostic>) {
+        let mut placed: Vec<&Run> = runs.iter().filter(|run| run.end > run.start).collect();
+        placed.sort_by_key(|run| run.start);
+        for pair in placed.windows(2) {
+            if pair[1].start < pair[0].end {
+                diagnostics.push(
+                    Diagnostic::error(
+                        DiagnosticCode::OverlappingBlocks,
+                        format!(
+                            "code at ${:04X}-${:04X} overlaps code at ${:04X}-${:04X}",
+                            pair[1].start,
+                            pair[1].end - 1,
+                            pair[0].start,
+                            pair[0].end - 1
+                        ),
+                    ).with_span(pair[1].tag)
+                        .with_label(pair[0].tag, "overlapped code starts here"),
+                );
+            }
+        }
+
+        for run in runs.iter().filter(|run| run.end > run.start) {
+            if let Some(output_position) = run.output_position {
+                let overlaps = placed
+                    .iter()
+                    .any(|other| other.tag != run.tag && other.start < run.end && run.start < other.end);
+                if !overlaps {
+                    diagnostics.push(
+                        Diagnostic::error(
+                            DiagnosticCode::OrgBelowOutput,
+                            format!(
+                                "code at ${:04X} is below the current output position ${:04X}",
+                                run.start, output_position
+                            ),
+                        ).with_span(run.tag)
+                            .with_note("use `.rorg` to run code at a different address than where it is loaded"),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Blocks that are laid out back to back, started by an `.org` or by the first block
+struct Run {
+    tag: SrcTag,
+    start: usize,
+    end: usize,
+    /// Where the run ended up in the output when that differs from its address
+    output_position: Option<usize>,
 }
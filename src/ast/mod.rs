@@ -9,54 +9,240 @@
 
 use std::sync::Arc;
 use lalrpop_util;
+use regex::Regex;
 
-use error;
+use diagnostic::{Diagnostic, DiagnosticCode, Span};
 use src_tag::SrcTag;
 use src_unit::SrcUnit;
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
 mod grammar;
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Number {
     Byte(u8),
     Word(u16),
     Invalid(usize),
 }
 
-#[derive(Debug)]
-pub enum Term {
+impl Number {
+    pub fn from_value(val: usize) -> Number {
+        if val <= 0xFF {
+            Number::Byte(val as u8)
+        } else if val <= 0xFFFF {
+            Number::Word(val as u16)
+        } else {
+            Number::Invalid(val)
+        }
+    }
+
+    pub fn value(&self) -> usize {
+        match *self {
+            Number::Byte(val) => val as usize,
+            Number::Word(val) => val as usize,
+            Number::Invalid(val) => val,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum UnaryOperator {
+    Negate,
+    Complement,
+    LogicalNot,
+    LowByte,
+    HighByte,
+    /// Bits 16 to 23, for addresses on CPUs with more than 64KB
+    BankByte,
+}
+
+/// Built-in function that takes any number of expressions
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Function {
+    Min,
+    Max,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BinaryOperator {
+    Multiply,
+    Divide,
+    Modulo,
+    Add,
+    Subtract,
+    ShiftLeft,
+    ShiftRight,
+    BitAnd,
+    BitXor,
+    BitOr,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    LogicalAnd,
+    LogicalOr,
+}
+
+impl BinaryOperator {
+    pub fn is_comparison(&self) -> bool {
+        use self::BinaryOperator::*;
+        match *self {
+            Equal | NotEqual | Less | LessEqual | Greater | GreaterEqual => true,
+            _ => false,
+        }
+    }
+}
+
+/// Expression in an operand or directive, whose names are resolved by the IR generator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Expr {
     Number(SrcTag, Number),
     Name(SrcTag, Arc<String>),
+    /// Address of the instruction or directive that contains the expression, written as `*` or `.here`
+    ProgramCounter(SrcTag),
+    Unary(SrcTag, UnaryOperator, Box<Expr>),
+    Binary(SrcTag, BinaryOperator, Box<Expr>, Box<Expr>),
+    /// Size in bytes of the named `.struct`, or of the code and data in the named scope
+    SizeOf(SrcTag, Arc<String>),
+    /// 1 if the name is defined anywhere in the program, otherwise 0
+    Defined(SrcTag, Arc<String>),
+    Call(SrcTag, Function, Vec<Expr>),
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum OperandModifier {
-    None,
-    HighByte,
-    LowByte,
+impl Expr {
+    pub fn from_value(tag: SrcTag, value: i64) -> Expr {
+        if value < 0 {
+            Expr::Unary(
+                tag,
+                UnaryOperator::Negate,
+                Box::new(Expr::Number(tag, Number::from_value(value.wrapping_neg() as usize))),
+            )
+        } else {
+            Expr::Number(tag, Number::from_value(value as usize))
+        }
+    }
+
+    pub fn contains_program_counter(&self) -> bool {
+        match *self {
+            Expr::ProgramCounter(_) => true,
+            Expr::Number(_, _) | Expr::Name(_, _) | Expr::SizeOf(_, _) | Expr::Defined(_, _) => false,
+            Expr::Unary(_, _, ref expr) => expr.contains_program_counter(),
+            Expr::Binary(_, _, ref left, ref right) => {
+                left.contains_program_counter() || right.contains_program_counter()
+            }
+            Expr::Call(_, _, ref arguments) => arguments.iter().any(Expr::contains_program_counter),
+        }
+    }
+
+    pub fn tag(&self) -> SrcTag {
+        match *self {
+            Expr::Number(tag, _)
+            | Expr::Name(tag, _)
+            | Expr::ProgramCounter(tag)
+            | Expr::Unary(tag, _, _)
+            | Expr::Binary(tag, _, _, _)
+            | Expr::SizeOf(tag, _)
+            | Expr::Defined(tag, _)
+            | Expr::Call(tag, _, _) => tag,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PrintItem {
+    Str(Arc<String>),
+    Expr(Expr),
+}
+
+/// Header field set by one of the `.ines` directives
+#[derive(Debug)]
+pub enum InesSetting {
+    PrgBanks(Number),
+    ChrBanks(Number),
+    Mapper(Number),
+    Mirroring(Arc<String>),
+    Battery,
 }
 
 #[derive(Debug)]
 pub enum Operand {
     None,
-    Immediate(OperandModifier, Term),
-    Address(OperandModifier, Term),
-    AbsoluteX(Term),
-    AbsoluteY(Term),
-    Indirect(Term),
-    IndirectX(Term),
-    IndirectY(Term),
+    Immediate(Expr),
+    Address(Expr),
+    AbsoluteX(Expr),
+    AbsoluteY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+/// Member of a `.struct`, which is given the offset of the bytes that it takes up
+#[derive(Debug)]
+pub struct StructMember {
+    pub tag: SrcTag,
+    /// Unnamed members only add padding
+    pub name: Option<Arc<String>>,
+    pub size: Number,
+}
+
+/// Member of an `.enum`, which is one more than the member before it unless it's given a value
+#[derive(Debug)]
+pub struct EnumMember {
+    pub tag: SrcTag,
+    pub name: Arc<String>,
+    pub value: Option<Expr>,
 }
 
 #[derive(Debug)]
 pub enum MetaInstruction {
     Org(SrcTag, Number),
-    Pad(SrcTag, Number),
-    Byte(SrcTag, Vec<Number>),
-    Word(SrcTag, Vec<Number>),
+    Rorg(SrcTag, Number),
+    EndRorg(SrcTag),
+    Pad(SrcTag, Number, Option<Number>),
+    Align(SrcTag, Number, Option<Number>),
+    Res(SrcTag, Number, Option<Number>),
+    Fill(SrcTag, Number, Number),
+    Byte(SrcTag, Vec<Expr>),
+    Word(SrcTag, Vec<Expr>),
     Vector(SrcTag, Arc<String>),
+    Indirect(SrcTag, Arc<String>),
     Include(SrcTag, Arc<String>),
+    /// Includes a file's bytes as data
+    IncBin(SrcTag, Arc<String>),
+    Assert(SrcTag, Expr, Option<Arc<String>>),
+    /// Assigns a variable that, unlike a constant, can be assigned again further on
+    Set(SrcTag, Arc<String>, Expr),
+    Print(SrcTag, Vec<PrintItem>),
+    Error(SrcTag, Arc<String>),
+    Warning(SrcTag, Arc<String>),
+    /// Opens a named scope that the names defined inside it belong to
+    Scope(SrcTag, Arc<String>),
+    EndScope(SrcTag),
+    /// Defines a label and opens a scope of the same name
+    Proc(SrcTag, Arc<String>),
+    EndProc(SrcTag),
+    Struct(SrcTag, Arc<String>, Vec<StructMember>),
+    Enum(SrcTag, Arc<String>, Vec<EnumMember>),
+    /// Switches the segment that code and data go into
+    Segment(SrcTag, Arc<String>),
+    /// Makes names defined in this unit visible to other units
+    Export(SrcTag, Vec<(SrcTag, Arc<String>)>),
+    /// Declares names that another unit exports, which are in the zero page when the flag is set
+    Import(SrcTag, Vec<(SrcTag, Arc<String>)>, bool),
+    /// Exports the names that this unit defines, and imports the rest
+    Global(SrcTag, Vec<(SrcTag, Arc<String>)>, bool),
+    /// Lets the linker leave out the enclosing `.proc`, or the current segment, if nothing refers to it
+    Removable(SrcTag),
+    /// Address that the loader jumps to once the program is loaded
+    Run(SrcTag, Expr),
+    /// Address that the loader calls as soon as it's loaded
+    Init(SrcTag, Expr),
+    /// Writes a BASIC line that runs the program from the named label with `SYS`
+    BasicStub(SrcTag, Arc<String>),
+    /// Sets a field of the iNES header
+    Ines(SrcTag, InesSetting),
 }
 
 #[derive(Debug)]
@@ -64,12 +250,14 @@ pub enum Statement {
     Error,
     Comment,
     Label(SrcTag, Arc<String>),
+    /// Immutable `name = expr` definition
+    Constant(SrcTag, Arc<String>, Expr),
     Instruction(SrcTag, Arc<String>, Operand),
     MetaInstruction(MetaInstruction),
 }
 
 impl Statement {
-    pub fn parse<'a>(src_unit: &'a SrcUnit) -> error::Result<Vec<Statement>> {
+    pub fn parse<'a>(src_unit: &'a SrcUnit) -> Result<Vec<Statement>, Vec<Diagnostic>> {
         if src_unit.source == "" {
             Ok(Vec::new())
         } else {
@@ -78,55 +266,94 @@ impl Statement {
             if errors.is_empty() {
                 Ok(ast.unwrap())
             } else {
-                Err(translate_errors(src_unit, errors.iter().map(|err| &err.error)).into())
+                Err(translate_errors(src_unit, errors.iter().map(|err| &err.error)))
             }
         }
     }
 }
 
-fn translate_errors<'a, I>(unit: &SrcUnit, errors: I) -> error::ErrorKind
+fn translate_errors<'a, I>(unit: &SrcUnit, errors: I) -> Vec<Diagnostic>
 where
     I: Iterator<Item = &'a lalrpop_util::ParseError<usize, grammar::Token<'a>, &'static str>>,
 {
-    let mut messages = Vec::new();
+    let mut diagnostics = Vec::new();
     for error in errors {
-        match *error {
+        diagnostics.push(match *error {
             lalrpop_util::ParseError::InvalidToken { location } => {
-                let (row, col) = SrcTag::new(0, location).row_col(&unit.source);
-                messages.push(format!("{}:{}:{}: invalid token", unit.name, row, col));
+                Diagnostic::error(DiagnosticCode::InvalidToken, "invalid token")
+                    .with_span(Span::new(unit.id, location, location + 1))
             }
             lalrpop_util::ParseError::UnrecognizedToken {
                 ref token,
                 ref expected,
             } => match *token {
-                Some((start, ref token, _end)) => {
-                    let (row, col) = SrcTag::new(0, start).row_col(&unit.source);
-                    messages.push(format!(
-                        "{}:{}:{}: unexpected token \"{}\". Expected one of: {:?}",
-                        unit.name, row, col, token.1, expected
-                    ));
-                }
+                Some((start, ref token, end)) => Diagnostic::error(
+                    DiagnosticCode::UnexpectedToken,
+                    format!("unexpected token \"{}\"", token.1),
+                ).with_span(Span::new(unit.id, start, end))
+                    .with_note(describe_expected(expected)),
                 None => {
-                    messages.push(format!(
-                        "{}: unexpected EOF; expected: {:?}",
-                        unit.name, expected
-                    ));
+                    let end = unit.source.len();
+                    Diagnostic::error(DiagnosticCode::UnexpectedEof, "unexpected EOF")
+                        .with_span(Span::new(unit.id, end, end))
+                        .with_note(describe_expected(expected))
                 }
             },
-            lalrpop_util::ParseError::ExtraToken { ref token } => {
-                let (row, col) = SrcTag::new(0, token.0).row_col(&unit.source);
-                messages.push(format!(
-                    "{}:{}:{}: extra token \"{}\"",
-                    unit.name,
-                    row,
-                    col,
-                    (token.1).1
-                ));
-            }
+            lalrpop_util::ParseError::ExtraToken { ref token } => Diagnostic::error(
+                DiagnosticCode::ExtraToken,
+                format!("extra token \"{}\"", (token.1).1),
+            ).with_span(Span::new(unit.id, token.0, token.2)),
             lalrpop_util::ParseError::User { ref error } => {
-                messages.push(format!("{:?}", error));
+                Diagnostic::error(DiagnosticCode::InvalidToken, format!("{}", error))
             }
+        });
+    }
+    diagnostics
+}
+
+/// Turns lalrpop's list of expected terminals into something readable, such as "expected `#`, name or number"
+fn describe_expected(expected: &[String]) -> String {
+    let mut names: Vec<String> = Vec::new();
+    for terminal in expected {
+        let name = if terminal.starts_with("r#\"") {
+            describe_pattern(&terminal[3..terminal.len() - 2])
+        } else {
+            format!("`{}`", terminal.trim_matches('"'))
+        };
+        if !names.contains(&name) {
+            names.push(name);
         }
     }
-    error::ErrorKind::ParseError(messages)
+
+    match names.len() {
+        0 => "expected nothing more here".into(),
+        1 => format!("expected {}", names[0]),
+        len => format!("expected one of {} or {}", names[..len - 1].join(", "), names[len - 1]),
+    }
+}
+
+/// Names a pattern terminal by the first sample token that it matches, so that the names don't depend on how
+/// the grammar writes its patterns
+fn describe_pattern(escaped: &str) -> String {
+    const SAMPLES: &[(&str, &str)] = &[
+        ("name", "name"),
+        ("label", "label:"),
+        ("instruction", "LDA"),
+        ("number", "42"),
+        ("number", "$2A"),
+        ("string", "\"text\""),
+        ("comment", "; comment"),
+    ];
+
+    // lalrpop escapes the pattern's backslashes when it lists it
+    let mut pattern = String::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        pattern.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+    }
+    Regex::new(&format!("^(?:{})$", pattern))
+        .ok()
+        .and_then(|regex| SAMPLES.iter().find(|&&(_, sample)| regex.is_match(sample)))
+        .map(|&(name, _)| name.into())
+        .unwrap_or_else(|| format!("text matching `{}`", pattern))
 }
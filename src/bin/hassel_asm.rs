@@ -7,22 +7,68 @@
 // copied, modified, or distributed except according to those terms.
 //
 
+extern crate atty;
 extern crate clap;
 extern crate hassel_asm;
 
-use hassel_asm::{error, Assembler};
+use hassel_asm::{error, Assembler, Linker};
+use hassel_asm::archive::Archive;
+use hassel_asm::diagnostic::{Diagnostic, Renderer};
+use hassel_asm::error::Warning;
+use hassel_asm::layout::MemoryLayout;
+use hassel_asm::format::OutputFormat;
+use hassel_asm::format::ines::{InesOptions, Mirroring};
+use hassel_asm::format::o65::O65File;
+use hassel_asm::object::ObjectFile;
+use hassel_asm::src_unit::SrcUnits;
+use hassel_asm::target::Cpu;
 
 use std::fs::File;
 use std::io::prelude::*;
 use std::process;
+use std::str::FromStr;
+
+#[derive(PartialEq)]
+enum Command {
+    /// Assembles a source file into a binary
+    Assemble,
+    /// Assembles a source file into an object file
+    Compile,
+    /// Links object files into a binary
+    Link,
+    /// Bundles object files into an archive
+    Archive,
+    /// Lists an archive's members and the names that each exports
+    Inspect,
+}
 
 struct Options {
-    input_name: String,
+    command: Command,
+    input_names: Vec<String>,
     output_name: Option<String>,
+    cpu: Cpu,
+    tab_width: usize,
+    fill_byte: u8,
+    layout_name: Option<String>,
+    format: OutputFormat,
+    ines: InesOptions,
+    entry_points: Vec<String>,
+    warnings: Vec<(Warning, bool)>,
+    warnings_as_errors: bool,
+}
+
+fn print_diagnostics(src_units: &SrcUnits, diagnostics: &[Diagnostic]) {
+    let renderer = Renderer::new(atty::is(atty::Stream::Stdout));
+    for diagnostic in diagnostics {
+        println!("{}", renderer.render(src_units, diagnostic));
+    }
 }
 
 fn die(err: &error::Error) -> ! {
-    println!("{}", err.0);
+    match err.0 {
+        error::ErrorKind::Diagnostics(ref src_units, ref diagnostics) => print_diagnostics(src_units, diagnostics),
+        _ => println!("{}", err.0),
+    }
     process::exit(1);
 }
 
@@ -33,64 +79,389 @@ fn handle_result<T>(result: error::Result<T>) -> T {
     }
 }
 
+/// Options shared by assembling and linking
+fn target_args<'a, 'b>() -> Vec<clap::Arg<'a, 'b>> {
+    vec![
+        clap::Arg::with_name("OUTPUT")
+            .short("o")
+            .long("output")
+            .value_name("OUTPUT")
+            .help("Sets output file name; otherwise outputs to STDOUT")
+            .takes_value(true),
+        clap::Arg::with_name("CPU")
+            .long("cpu")
+            .value_name("CPU")
+            .help("Sets the target processor; defaults to 6502")
+            .possible_values(&["6502", "65c02"])
+            .takes_value(true),
+        clap::Arg::with_name("FILL")
+            .long("fill")
+            .value_name("FILL")
+            .help("Sets the byte used for gaps and padding, such as $FF for EPROMs; defaults to 0")
+            .takes_value(true),
+        clap::Arg::with_name("LAYOUT")
+            .long("layout")
+            .value_name("LAYOUT")
+            .help("Sets a JSON memory layout that places segments into memory areas")
+            .takes_value(true),
+        clap::Arg::with_name("FORMAT")
+            .long("format")
+            .value_name("FORMAT")
+            .help("Sets the output file format; defaults to bin")
+            .possible_values(&["bin", "o65", "ihex", "srec", "ines", "nes2", "prg", "xex"])
+            .takes_value(true),
+        clap::Arg::with_name("INES_PRG")
+            .long("ines-prg")
+            .value_name("BANKS")
+            .help("Sets the number of 16 KiB PRG ROM banks in the iNES header")
+            .takes_value(true),
+        clap::Arg::with_name("INES_CHR")
+            .long("ines-chr")
+            .value_name("BANKS")
+            .help("Sets the number of 8 KiB CHR ROM banks in the iNES header")
+            .takes_value(true),
+        clap::Arg::with_name("INES_MAPPER")
+            .long("ines-mapper")
+            .value_name("MAPPER")
+            .help("Sets the mapper number in the iNES header")
+            .takes_value(true),
+        clap::Arg::with_name("INES_MIRRORING")
+            .long("ines-mirroring")
+            .value_name("MIRRORING")
+            .help("Sets the nametable mirroring in the iNES header")
+            .possible_values(&["horizontal", "vertical", "four_screen"])
+            .takes_value(true),
+        clap::Arg::with_name("INES_BATTERY")
+            .long("ines-battery")
+            .help("Marks the cartridge as having battery-backed RAM in the iNES header"),
+        clap::Arg::with_name("TAB_WIDTH")
+            .long("tab-width")
+            .value_name("TAB_WIDTH")
+            .help("Sets the tab width used for column numbers in messages; defaults to 1")
+            .takes_value(true),
+        clap::Arg::with_name("WARNING")
+            .short("W")
+            .value_name("WARNING")
+            .help(
+                "Enables a warning with -W<name>, disables it with -Wno-<name>, \
+                 or treats all warnings as errors with -Werror",
+            )
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
+
 fn get_options() -> Options {
     let cli_app = clap::App::new("hassel_asm")
         .version("v0.1.0")
         .author("John DiSanti <johndisanti@gmail.com>")
         .about("6502 Assembler")
+        .setting(clap::AppSettings::SubcommandsNegateReqs)
+        .args(&target_args())
         .arg(
-            clap::Arg::with_name("OUTPUT")
-                .short("o")
-                .long("output")
-                .value_name("OUTPUT")
-                .help("Sets output file name; otherwise outputs to STDOUT")
-                .takes_value(true),
+            clap::Arg::with_name("OBJECT")
+                .short("c")
+                .long("object")
+                .help("Writes an object file to link with `hassel_asm link` instead of a binary"),
         )
         .arg(
             clap::Arg::with_name("INPUT")
                 .help("Input source file to use")
                 .required(true),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("link")
+                .about("Links object files into a binary")
+                .args(&target_args())
+                .arg(
+                    clap::Arg::with_name("ENTRY")
+                        .long("entry")
+                        .value_name("ENTRY")
+                        .help("Keeps the removable code that an exported name is in")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    clap::Arg::with_name("INPUT")
+                        .help(
+                            "Object and o65 files to link, in the order that their code is placed, and archives to \
+                             pull members from",
+                        )
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("archive")
+                .about("Bundles object files into an archive")
+                .arg(
+                    clap::Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output")
+                        .value_name("OUTPUT")
+                        .help("Sets archive file name; defaults to out.lib")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("INPUT")
+                        .help("Object files to add")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("inspect")
+                .about("Lists an archive's members and the names that each exports")
+                .arg(
+                    clap::Arg::with_name("INPUT")
+                        .help("Archive to list")
+                        .required(true),
+                ),
         );
-    let cli_matches = cli_app.get_matches();
+    let app_matches = cli_app.get_matches();
+    let (command, cli_matches) = match app_matches.subcommand() {
+        ("link", Some(link_matches)) => (Command::Link, link_matches),
+        ("archive", Some(archive_matches)) => (Command::Archive, archive_matches),
+        ("inspect", Some(inspect_matches)) => (Command::Inspect, inspect_matches),
+        _ if app_matches.is_present("OBJECT") => (Command::Compile, &app_matches),
+        _ => (Command::Assemble, &app_matches),
+    };
+
+    let mut warnings = Vec::new();
+    let mut warnings_as_errors = false;
+    for flag in cli_matches.values_of("WARNING").into_iter().flat_map(|values| values) {
+        if flag == "error" {
+            warnings_as_errors = true;
+            continue;
+        }
+        let (name, enabled) = if flag.starts_with("no-") {
+            (&flag[3..], false)
+        } else {
+            (flag, true)
+        };
+        match Warning::from_name(name) {
+            Some(warning) => warnings.push((warning, enabled)),
+            None => {
+                let names: Vec<&str> = Warning::all().iter().map(|warning| warning.name()).collect();
+                println!("Unknown warning \"{}\"; expected one of: {}", name, names.join(", "));
+                process::exit(1);
+            }
+        }
+    }
+
+    let tab_width = match cli_matches.value_of("TAB_WIDTH").map(usize::from_str) {
+        Some(Ok(tab_width)) if tab_width > 0 => tab_width,
+        Some(_) => {
+            println!("Tab width must be a positive number");
+            process::exit(1);
+        }
+        None => 1,
+    };
+
+    let fill_byte = match cli_matches.value_of("FILL").map(parse_byte) {
+        Some(Some(fill_byte)) => fill_byte,
+        Some(None) => {
+            println!("Fill must be a byte, such as 255 or $FF");
+            process::exit(1);
+        }
+        None => 0,
+    };
+
+    let ines = InesOptions {
+        prg_banks: number_option(&cli_matches, "INES_PRG", "PRG bank count"),
+        chr_banks: number_option(&cli_matches, "INES_CHR", "CHR bank count"),
+        mapper: number_option(&cli_matches, "INES_MAPPER", "Mapper"),
+        mirroring: cli_matches
+            .value_of("INES_MIRRORING")
+            .and_then(Mirroring::from_name),
+        battery: if cli_matches.is_present("INES_BATTERY") {
+            Some(true)
+        } else {
+            None
+        },
+    };
 
     Options {
-        input_name: cli_matches.value_of("INPUT").unwrap().into(),
+        command: command,
+        input_names: cli_matches.values_of("INPUT").unwrap().map(String::from).collect(),
         output_name: cli_matches.value_of("OUTPUT").map(String::from),
+        cpu: cli_matches
+            .value_of("CPU")
+            .and_then(Cpu::from_name)
+            .unwrap_or_default(),
+        tab_width: tab_width,
+        fill_byte: fill_byte,
+        layout_name: cli_matches.value_of("LAYOUT").map(String::from),
+        format: cli_matches
+            .value_of("FORMAT")
+            .and_then(OutputFormat::from_name)
+            .unwrap_or_default(),
+        ines: ines,
+        entry_points: cli_matches
+            .values_of("ENTRY")
+            .into_iter()
+            .flat_map(|values| values.map(String::from))
+            .collect(),
+        warnings: warnings,
+        warnings_as_errors: warnings_as_errors,
     }
 }
 
+/// Parses a byte written in decimal, or in hex with a `$` or `0x` prefix
+fn parse_byte(value: &str) -> Option<u8> {
+    if value.starts_with('$') {
+        u8::from_str_radix(&value[1..], 16).ok()
+    } else if value.starts_with("0x") {
+        u8::from_str_radix(&value[2..], 16).ok()
+    } else {
+        u8::from_str(value).ok()
+    }
+}
+
+/// Reads an option that's a 16-bit number, exiting if it's given but isn't one
+fn number_option(cli_matches: &clap::ArgMatches, name: &str, what: &str) -> Option<u16> {
+    cli_matches.value_of(name).map(|value| match u16::from_str(value) {
+        Ok(number) => number,
+        Err(_) => {
+            println!("{} must be a number from 0 to 65535", what);
+            process::exit(1);
+        }
+    })
+}
+
+/// Writes the archive, or prints the members of an existing one
+fn run_archive_command(options: &Options) {
+    if options.command == Command::Inspect {
+        let archive = handle_result(read_file(&options.input_names[0]).and_then(|json| Archive::from_json(&json)));
+        for (member_index, member) in archive.members().iter().enumerate() {
+            let exports: Vec<&str> = archive
+                .index()
+                .iter()
+                .filter(|&(_, &index)| index == member_index)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            println!("{}: {}", member.name, exports.join(", "));
+        }
+        return;
+    }
+
+    let mut archive = Archive::new();
+    for input_name in &options.input_names {
+        let object = handle_result(read_file(input_name).and_then(|json| ObjectFile::from_json(&json)));
+        handle_result(archive.add_member(input_name.as_str(), object));
+    }
+    let output_file_name = options.output_name.clone().unwrap_or_else(|| "out.lib".into());
+    save_bytes(&output_file_name, handle_result(archive.to_json()).as_bytes());
+}
+
 pub fn main() {
     let options = get_options();
+    if options.command == Command::Archive || options.command == Command::Inspect {
+        run_archive_command(&options);
+        return;
+    }
+    let layout = options
+        .layout_name
+        .as_ref()
+        .map(|layout_name| handle_result(read_file(layout_name).and_then(|json| MemoryLayout::from_json(&json))));
 
-    let input_source = {
-        let mut file = match File::open(&options.input_name) {
-            Ok(file) => file,
+    let assembler_output = if options.command == Command::Link {
+        let mut linker = Linker::new();
+        linker.set_cpu(options.cpu);
+        linker.set_tab_width(options.tab_width);
+        linker.set_fill_byte(options.fill_byte);
+        linker.set_output_format(options.format);
+        linker.set_ines_options(options.ines.clone());
+        if let Some(layout) = layout {
+            handle_result(linker.set_layout(layout));
+        }
+        for &(warning, enabled) in &options.warnings {
+            linker.set_warning(warning, enabled);
+        }
+        linker.set_warnings_as_errors(options.warnings_as_errors);
+        for entry_point in &options.entry_points {
+            linker.add_entry_point(entry_point.as_str());
+        }
+        for input_name in &options.input_names {
+            let bytes = handle_result(read_bytes(input_name));
+            if O65File::is_o65(&bytes) {
+                let o65 = handle_result(O65File::from_bytes(&bytes));
+                linker.add_object(handle_result(o65.to_object(input_name)));
+                continue;
+            }
+            let json = String::from_utf8_lossy(&bytes);
+            if Archive::is_archive(&json) {
+                linker.add_archive(handle_result(Archive::from_json(&json)));
+            } else {
+                linker.add_object(handle_result(ObjectFile::from_json(&json)));
+            }
+        }
+        handle_result(linker.link())
+    } else {
+        let input_name = &options.input_names[0];
+        let input_source = match read_file(input_name) {
+            Ok(contents) => contents,
             Err(e) => {
-                println!("Failed to open input source file: {}", e);
+                println!("Failed to read the input source file: {}", e);
                 return;
             }
         };
-        let mut file_contents = String::new();
-        if !file.read_to_string(&mut file_contents).is_ok() {
-            println!("Failed to read the input source file");
+
+        let mut assembler = Assembler::new();
+        assembler.set_cpu(options.cpu);
+        assembler.set_tab_width(options.tab_width);
+        assembler.set_fill_byte(options.fill_byte);
+        assembler.set_output_format(options.format);
+        assembler.set_ines_options(options.ines.clone());
+        if let Some(layout) = layout {
+            handle_result(assembler.set_layout(layout));
+        }
+        for &(warning, enabled) in &options.warnings {
+            assembler.set_warning(warning, enabled);
+        }
+        assembler.set_warnings_as_errors(options.warnings_as_errors);
+        handle_result(assembler.parse_unit(input_name, &input_source));
+
+        if options.command == Command::Compile {
+            let object = handle_result(assembler.compile());
+            let mut src_units = object.src_units();
+            src_units.set_tab_width(options.tab_width);
+            print_diagnostics(&src_units, &object.diagnostics);
+            let output_file_name = options.output_name.unwrap_or_else(|| "out.o".into());
+            save_bytes(&output_file_name, handle_result(object.to_json()).as_bytes());
             return;
         }
-        file_contents
+        handle_result(assembler.assemble())
     };
-
-    let mut assembler = Assembler::new();
-    handle_result(assembler.parse_unit(&options.input_name, &input_source));
-
-    let assembler_output = handle_result(assembler.assemble());
+    print_diagnostics(&assembler_output.src_units, &assembler_output.diagnostics);
 
     let output_file_name = options.output_name.unwrap_or_else(|| "out.rom".into());
     let source_map_file_name = format!("{}.map", output_file_name);
+    let symbols_file_name = format!("{}.sym", output_file_name);
 
     save_bytes(&output_file_name, &assembler_output.bytes.unwrap());
     save_bytes(
         &source_map_file_name,
         &assembler_output.source_map.unwrap().as_bytes(),
     );
+    save_bytes(&symbols_file_name, &assembler_output.symbols.unwrap().as_bytes());
+    if let Some(removed) = assembler_output.removed {
+        save_bytes(&format!("{}.removed", output_file_name), removed.as_bytes());
+    }
+}
+
+fn read_file(file_name: &str) -> error::Result<String> {
+    let mut contents = String::new();
+    File::open(file_name)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+fn read_bytes(file_name: &str) -> error::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(file_name)?.read_to_end(&mut contents)?;
+    Ok(contents)
 }
 
 fn save_bytes(file_name: &str, bytes: &[u8]) {
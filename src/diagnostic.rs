@@ -0,0 +1,393 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use error::Warning;
+use src_tag::SrcTag;
+use src_unit::SrcUnits;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Note => "note",
+        }
+    }
+}
+
+/// Identifies the kind of problem a diagnostic reports so that tools can match on it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum DiagnosticCode {
+    InvalidToken,
+    UnexpectedToken,
+    UnexpectedEof,
+    ExtraToken,
+    IncludeFailed,
+    UnknownOpcode,
+    InvalidAddressMode,
+    NumberOutOfRange,
+    OperandSize,
+    DivisionByZero,
+    UnknownLabel,
+    BranchOutOfRange,
+    ProgramTooLarge,
+    JmpIndirectPageWrap,
+    AddressOverflow,
+    OverlappingBlocks,
+    UnplacedBlock,
+    PadBackwards,
+    UnbalancedRelocation,
+    OrgBelowOutput,
+    DuplicateSymbol,
+    CircularDefinition,
+    UnbalancedScope,
+    QualifiedDefinition,
+    UnknownSegment,
+    AreaOverflow,
+    OrgWithLayout,
+    UndefinedExport,
+    UndefinedImport,
+    DuplicateExport,
+    MisplacedRemovable,
+    NotRelocatable,
+    UnknownMirroring,
+    AssertionFailed,
+    UserError,
+    /// Output from `.print`
+    Message,
+    Warning(Warning),
+}
+
+impl DiagnosticCode {
+    pub fn as_str(&self) -> &'static str {
+        use self::DiagnosticCode::*;
+        match *self {
+            InvalidToken => "E0001",
+            UnexpectedToken => "E0002",
+            UnexpectedEof => "E0003",
+            ExtraToken => "E0004",
+            IncludeFailed => "E0005",
+            UnknownOpcode => "E0100",
+            InvalidAddressMode => "E0101",
+            NumberOutOfRange => "E0102",
+            OperandSize => "E0104",
+            DivisionByZero => "E0105",
+            UnknownLabel => "E0200",
+            BranchOutOfRange => "E0201",
+            ProgramTooLarge => "E0202",
+            JmpIndirectPageWrap => "E0203",
+            AddressOverflow => "E0204",
+            OverlappingBlocks => "E0205",
+            UnplacedBlock => "E0206",
+            PadBackwards => "E0207",
+            UnbalancedRelocation => "E0208",
+            OrgBelowOutput => "E0209",
+            DuplicateSymbol => "E0210",
+            CircularDefinition => "E0211",
+            UnbalancedScope => "E0212",
+            QualifiedDefinition => "E0213",
+            UnknownSegment => "E0214",
+            AreaOverflow => "E0215",
+            OrgWithLayout => "E0216",
+            UndefinedExport => "E0217",
+            UndefinedImport => "E0218",
+            DuplicateExport => "E0219",
+            MisplacedRemovable => "E0220",
+            NotRelocatable => "E0221",
+            UnknownMirroring => "E0222",
+            AssertionFailed => "E0300",
+            UserError => "E0301",
+            Message => "N0001",
+            DiagnosticCode::Warning(warning) => warning.code(),
+        }
+    }
+}
+
+/// A range of characters in one source unit; `end` is exclusive and may equal `start`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct Span {
+    pub unit: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(unit: usize, start: usize, end: usize) -> Span {
+        Span {
+            unit: unit,
+            start: start,
+            end: end,
+        }
+    }
+
+    pub fn tag(&self) -> SrcTag {
+        SrcTag::new(self.unit, self.start)
+    }
+}
+
+impl From<SrcTag> for Span {
+    fn from(tag: SrcTag) -> Span {
+        Span::new(tag.unit, tag.offset, tag.offset)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: DiagnosticCode,
+    pub message: String,
+    pub primary: Option<Span>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new<M: Into<String>>(severity: Severity, code: DiagnosticCode, message: M) -> Diagnostic {
+        Diagnostic {
+            severity: severity,
+            code: code,
+            message: message.into(),
+            primary: None,
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error<M: Into<String>>(code: DiagnosticCode, message: M) -> Diagnostic {
+        Diagnostic::new(Severity::Error, code, message)
+    }
+
+    pub fn warning<M: Into<String>>(warning: Warning, message: M) -> Diagnostic {
+        Diagnostic::new(Severity::Warning, DiagnosticCode::Warning(warning), message)
+    }
+
+    pub fn with_span<S: Into<Span>>(mut self, span: S) -> Diagnostic {
+        self.primary = Some(span.into());
+        self
+    }
+
+    pub fn with_label<S: Into<Span>, M: Into<String>>(mut self, span: S, message: M) -> Diagnostic {
+        self.secondary.push(Label {
+            span: span.into(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_note<M: Into<String>>(mut self, note: M) -> Diagnostic {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    /// Renders the diagnostic without colour; see `Renderer` for terminal output
+    pub fn render(&self, src_units: &SrcUnits) -> String {
+        Renderer::new(false).render(src_units, self)
+    }
+}
+
+const STYLE_ERROR: &str = "\x1b[1;31m";
+const STYLE_WARNING: &str = "\x1b[1;33m";
+const STYLE_NOTE: &str = "\x1b[1;32m";
+const STYLE_GUTTER: &str = "\x1b[1;34m";
+const STYLE_BOLD: &str = "\x1b[1m";
+const STYLE_RESET: &str = "\x1b[0m";
+
+/// Renders diagnostics rustc-style, with the offending source line and an underline beneath the span
+pub struct Renderer {
+    colour: bool,
+}
+
+impl Renderer {
+    pub fn new(colour: bool) -> Renderer {
+        Renderer { colour: colour }
+    }
+
+    pub fn render(&self, src_units: &SrcUnits, diagnostic: &Diagnostic) -> String {
+        let severity_style = match diagnostic.severity {
+            Severity::Error => STYLE_ERROR,
+            Severity::Warning => STYLE_WARNING,
+            Severity::Note => STYLE_NOTE,
+        };
+        let mut result = format!(
+            "{}{}",
+            self.paint(
+                severity_style,
+                &format!("{}[{}]", diagnostic.severity.name(), diagnostic.code.as_str())
+            ),
+            self.paint(STYLE_BOLD, &format!(": {}", diagnostic.message))
+        );
+
+        let gutter_width = diagnostic
+            .primary
+            .iter()
+            .chain(diagnostic.secondary.iter().map(|label| &label.span))
+            .map(|span| src_units.unit(span.unit).row(span.start).to_string().len())
+            .max()
+            .unwrap_or(0);
+        let blank_gutter = " ".repeat(gutter_width);
+
+        if let Some(span) = diagnostic.primary {
+            result.push_str(&format!(
+                "\n{}{} {}",
+                blank_gutter,
+                self.paint(STYLE_GUTTER, "-->"),
+                location(src_units, span)
+            ));
+            result.push_str(&self.snippet(src_units, span, '^', severity_style, "", gutter_width));
+        }
+        for label in &diagnostic.secondary {
+            if diagnostic.primary.map(|span| span.unit) != Some(label.span.unit) {
+                result.push_str(&format!(
+                    "\n{}{} {}",
+                    blank_gutter,
+                    self.paint(STYLE_GUTTER, ":::"),
+                    location(src_units, label.span)
+                ));
+            }
+            result.push_str(&self.snippet(
+                src_units,
+                label.span,
+                '-',
+                STYLE_GUTTER,
+                &label.message,
+                gutter_width,
+            ));
+        }
+        for note in &diagnostic.notes {
+            result.push_str(&format!(
+                "\n{} {} {}",
+                blank_gutter,
+                self.paint(STYLE_GUTTER, "="),
+                self.paint(STYLE_BOLD, "note:")
+            ));
+            result.push_str(&format!(" {}", note));
+        }
+        result
+    }
+
+    fn snippet(
+        &self,
+        src_units: &SrcUnits,
+        span: Span,
+        marker: char,
+        marker_style: &str,
+        message: &str,
+        gutter_width: usize,
+    ) -> String {
+        let unit = src_units.unit(span.unit);
+        let source = &unit.source;
+        let start = span.start.min(source.len());
+        let row = unit.row(start);
+        let line_start = unit.line_start(row);
+        let line = SrcTag::new(span.unit, line_start).line(source);
+        let line_end = line_start + line.len();
+
+        // Zero-width spans come from `SrcTag`s, so underline the token that starts there
+        let mut end = span.end.min(line_end);
+        if end <= start {
+            end = source[start..line_end]
+                .find(|chr: char| chr.is_whitespace() || chr == ',' || chr == ')')
+                .map(|index| start + index)
+                .unwrap_or(line_end);
+        }
+        while !source.is_char_boundary(end) {
+            end += 1;
+        }
+
+        // Keep tabs in the indentation so that the underline lines up with the source
+        let indent: String = source[line_start..start]
+            .chars()
+            .map(|chr| if chr == '\t' { '\t' } else { ' ' })
+            .collect();
+        let underline_len = ::std::cmp::max(1, source[start..end].chars().count());
+        let underline: String = ::std::iter::repeat(marker).take(underline_len).collect();
+
+        let blank_gutter = " ".repeat(gutter_width);
+        let bar = self.paint(STYLE_GUTTER, "|");
+        let mut result = format!("\n{} {}", blank_gutter, bar);
+        result.push_str(&format!(
+            "\n{} {} {}",
+            self.paint(STYLE_GUTTER, &format!("{:>width$}", row, width = gutter_width)),
+            bar,
+            line
+        ));
+        result.push_str(&format!("\n{} {} {}", blank_gutter, bar, indent));
+        if message.is_empty() {
+            result.push_str(&self.paint(marker_style, &underline));
+        } else {
+            result.push_str(&self.paint(marker_style, &format!("{} {}", underline, message)));
+        }
+        result
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", style, text, STYLE_RESET)
+        } else {
+            text.into()
+        }
+    }
+}
+
+fn location(src_units: &SrcUnits, span: Span) -> String {
+    let (row, col) = src_units.row_col(span.tag());
+    format!("{}:{}:{}", src_units.name(span.unit), row, col)
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(Diagnostic::is_error)
+}
+
+pub fn render_all(src_units: &SrcUnits, diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.render(src_units))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_snippet() {
+        let mut src_units = SrcUnits::new();
+        src_units.push_unit("test.s".into(), "LDA #1\n\tJMP foo\n".into());
+        let diagnostic = Diagnostic::error(DiagnosticCode::UnknownLabel, "unknown label: \"foo\"")
+            .with_span(SrcTag::new(0, 12))
+            .with_note("labels are case sensitive");
+
+        let expected = "\
+error[E0200]: unknown label: \"foo\"
+ --> test.s:2:6
+  |
+2 | \tJMP foo
+  | \t    ^^^
+  = note: labels are case sensitive";
+        assert_eq!(expected, diagnostic.render(&src_units));
+    }
+}
@@ -7,7 +7,9 @@
 // copied, modified, or distributed except according to those terms.
 //
 
-use src_tag::SrcTag;
+use std::collections::HashSet;
+
+use diagnostic::{self, Diagnostic, DiagnosticCode, Severity};
 use src_unit::SrcUnits;
 
 error_chain! {
@@ -25,33 +27,156 @@ error_chain! {
     }
 
     errors {
-        ParseError(errors: Vec<String>) {
-            description("Failed to parse assembly")
-            display("Failed to parse assembly:\n{}", errors.join("\n"))
-        }
-        AssemblerError(src_tag: SrcTag, msg: String) {
+        Diagnostics(src_units: SrcUnits, diagnostics: Vec<Diagnostic>) {
             description("Failed to assemble")
-            display("{}", msg)
+            display("{}", diagnostic::render_all(src_units, diagnostics))
         }
-        SrcUnitError(msg: String) {
-            description("Failed to assemble")
-            display("{}", msg)
+        InvalidLayout(message: String) {
+            description("Invalid memory layout")
+            display("invalid memory layout: {}", message)
+        }
+        InvalidObject(message: String) {
+            description("Invalid object file")
+            display("invalid object file: {}", message)
+        }
+        InvalidArchive(message: String) {
+            description("Invalid archive")
+            display("invalid archive: {}", message)
+        }
+        InvalidO65(message: String) {
+            description("Invalid o65 file")
+            display("invalid o65 file: {}", message)
+        }
+        InvalidHexFile(message: String) {
+            description("Invalid hex file")
+            display("invalid hex file: {}", message)
+        }
+        InvalidRomImage(message: String) {
+            description("Invalid ROM image")
+            display("can't write ROM image: {}", message)
+        }
+        UnknownEntryPoint(name: String) {
+            description("Unknown entry point")
+            display("entry point \"{}\" isn't exported by any object", name)
+        }
+    }
+}
+
+/// Categories of suspicious but legal code that the assembler can warn about
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize)]
+pub enum Warning {
+    BranchPageCross,
+    UnusedLabel,
+    ImplicitZeroPage,
+    JmpIndirectPageWrap,
+    User,
+}
+
+impl Warning {
+    pub fn all() -> &'static [Warning] {
+        static ALL: [Warning; 5] = [
+            Warning::BranchPageCross,
+            Warning::UnusedLabel,
+            Warning::ImplicitZeroPage,
+            Warning::JmpIndirectPageWrap,
+            Warning::User,
+        ];
+        &ALL
+    }
+
+    /// Name used by the `-W<name>` and `-Wno-<name>` command line flags
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Warning::BranchPageCross => "branch-page-cross",
+            Warning::UnusedLabel => "unused-label",
+            Warning::ImplicitZeroPage => "implicit-zero-page",
+            Warning::JmpIndirectPageWrap => "jmp-indirect-page-wrap",
+            Warning::User => "user",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Warning::BranchPageCross => "W0001",
+            Warning::UnusedLabel => "W0002",
+            Warning::ImplicitZeroPage => "W0003",
+            Warning::JmpIndirectPageWrap => "W0004",
+            Warning::User => "W0005",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Warning> {
+        Warning::all()
+            .iter()
+            .find(|warning| warning.name() == name)
+            .cloned()
+    }
+
+    pub fn enabled_by_default(&self) -> bool {
+        match *self {
+            Warning::BranchPageCross | Warning::UnusedLabel | Warning::ImplicitZeroPage => false,
+            Warning::JmpIndirectPageWrap | Warning::User => true,
+        }
+    }
+}
+
+/// Decides which warnings are reported, and whether they are reported as errors
+#[derive(Debug, Clone)]
+pub struct WarningConfig {
+    enabled: HashSet<Warning>,
+    warnings_as_errors: bool,
+}
+
+impl Default for WarningConfig {
+    fn default() -> WarningConfig {
+        WarningConfig {
+            enabled: Warning::all()
+                .iter()
+                .filter(|warning| warning.enabled_by_default())
+                .cloned()
+                .collect(),
+            warnings_as_errors: false,
         }
     }
 }
 
-pub fn format_error(src_units: &SrcUnits, error: &Error) -> String {
-    match error.0 {
-        ErrorKind::AssemblerError(ref src_tag, ref msg) => {
-            let row_col = src_tag.row_col(src_units.source(src_tag.unit));
-            format!(
-                "{}:{}:{}: {}",
-                src_units.name(src_tag.unit).clone(),
-                row_col.0,
-                row_col.1,
-                msg
-            )
-        }
-        _ => format!("{}", error),
+impl WarningConfig {
+    pub fn new() -> WarningConfig {
+        Default::default()
+    }
+
+    pub fn set_enabled(&mut self, warning: Warning, enabled: bool) {
+        if enabled {
+            self.enabled.insert(warning);
+        } else {
+            self.enabled.remove(&warning);
+        }
+    }
+
+    pub fn is_enabled(&self, warning: Warning) -> bool {
+        self.enabled.contains(&warning)
+    }
+
+    pub fn set_warnings_as_errors(&mut self, warnings_as_errors: bool) {
+        self.warnings_as_errors = warnings_as_errors;
+    }
+
+    /// Drops disabled warnings from the given diagnostics, and promotes the rest to errors if requested
+    pub fn apply(&self, diagnostics: Vec<Diagnostic>) -> Vec<Diagnostic> {
+        diagnostics
+            .into_iter()
+            .filter_map(|diagnostic| match diagnostic.code {
+                DiagnosticCode::Warning(warning) if !self.is_enabled(warning) => None,
+                DiagnosticCode::Warning(warning) if self.warnings_as_errors => {
+                    let mut promoted = diagnostic.with_note(format!(
+                        "`-W{}` is treated as an error because of `-Werror`",
+                        warning.name()
+                    ));
+                    promoted.severity = Severity::Error;
+                    Some(promoted)
+                }
+                _ => Some(diagnostic),
+            })
+            .collect()
     }
 }
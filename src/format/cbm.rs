@@ -0,0 +1,33 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use ir::IR;
+use target::Target;
+
+/// Writes a Commodore program file, which is the image preceded by the little-endian address that it's loaded at
+pub fn write(ir: &IR, target: &Target, binary: Vec<u8>) -> Vec<u8> {
+    let address = load_address(ir, target);
+    let mut bytes = vec![address as u8, (address >> 8) as u8];
+    bytes.extend(binary);
+    bytes
+}
+
+/// Returns where the image starts: the first output area with a layout, or else the first block that produces
+/// output
+fn load_address(ir: &IR, target: &Target) -> u16 {
+    let address = match target.layout {
+        Some(ref layout) => layout.areas.iter().find(|area| area.output).map(|area| area.start),
+        None => ir.blocks
+            .iter()
+            .zip(ir.block_outputs())
+            .find(|&(_, output)| output)
+            .and_then(|(block, _)| block.load_position),
+    };
+    address.unwrap_or(0)
+}
@@ -0,0 +1,86 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+//! Intel HEX, which is a line for each record: `:`, then hex digits for the length of the data, the address, the
+//! record type, the data, and a checksum that makes the bytes add up to zero.
+
+use error;
+use format::{decode_hex, invalid, join_segments, push_record, ImageSegment};
+
+/// Most data bytes that a record is written with
+const RECORD_SIZE: usize = 16;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Writes a data record for up to every 16 bytes of each segment, then the end of file record
+pub fn write(segments: &[ImageSegment]) -> String {
+    let mut text = String::new();
+    for segment in segments {
+        for (index, data) in segment.bytes.chunks(RECORD_SIZE).enumerate() {
+            let address = segment.address as usize + index * RECORD_SIZE;
+            push(&mut text, address as u16, DATA, data);
+        }
+    }
+    push(&mut text, 0, END_OF_FILE, &[]);
+    text
+}
+
+fn push(text: &mut String, address: u16, kind: u8, data: &[u8]) {
+    let mut record = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    record.push(sum.wrapping_neg());
+    push_record(text, ":", &record);
+}
+
+/// Reads the data records up to the end of file record, joining records that follow each other. Extended address
+/// records are followed, but the data has to be within the first 64KB.
+pub fn read(text: &str) -> error::Result<Vec<ImageSegment>> {
+    let mut records = Vec::new();
+    let mut base = 0usize;
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(':') {
+            return Err(invalid(format!("line {} doesn't start with ':'", number)));
+        }
+        let bytes = decode_hex(&line[1..], number)?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid(format!("line {} has the wrong length", number)));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+            return Err(invalid(format!("line {} has the wrong checksum", number)));
+        }
+        let address = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            DATA => {
+                let start = base + address;
+                if start + data.len() > 0x10000 {
+                    return Err(invalid(format!("line {} has data past $FFFF", number)));
+                }
+                records.push(ImageSegment::new(start as u16, data.to_vec()));
+            }
+            END_OF_FILE => return Ok(join_segments(records)),
+            EXTENDED_SEGMENT_ADDRESS if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+            EXTENDED_LINEAR_ADDRESS if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            kind => return Err(invalid(format!("line {} has unknown record type {:02X}", number, kind))),
+        }
+    }
+    Err(invalid("it has no end of file record"))
+}
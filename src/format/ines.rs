@@ -0,0 +1,186 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use error;
+use ir::IR;
+use target::Target;
+
+/// Size of each bank of program ROM
+pub const PRG_BANK_SIZE: usize = 0x4000;
+/// Size of each bank of character ROM
+pub const CHR_BANK_SIZE: usize = 0x2000;
+/// Segment whose memory area is written as the character ROM
+pub const CHR_SEGMENT: &str = "CHR";
+
+const MAGIC: &[u8] = b"NES\x1A";
+const HEADER_SIZE: usize = 16;
+
+/// How the console mirrors the PPU's nametables
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    /// The cartridge provides all four nametables
+    FourScreen,
+}
+
+impl Mirroring {
+    pub fn all() -> &'static [Mirroring] {
+        static ALL: [Mirroring; 3] = [Mirroring::Horizontal, Mirroring::Vertical, Mirroring::FourScreen];
+        &ALL
+    }
+
+    /// Name used by `.inesmir` and the `--ines-mirroring` command line option
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mirroring::Horizontal => "horizontal",
+            Mirroring::Vertical => "vertical",
+            Mirroring::FourScreen => "four_screen",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Mirroring> {
+        Mirroring::all()
+            .iter()
+            .find(|mirroring| mirroring.name() == name)
+            .cloned()
+    }
+}
+
+/// Header settings, given by directives such as `.inesmap` or on the command line. Bank counts that aren't
+/// given are worked out from the size of the ROM data.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct InesOptions {
+    /// Number of 16 KiB program ROM banks
+    pub prg_banks: Option<u16>,
+    /// Number of 8 KiB character ROM banks; none means that the cartridge has character RAM
+    pub chr_banks: Option<u16>,
+    pub mapper: Option<u16>,
+    pub mirroring: Option<Mirroring>,
+    /// Whether the cartridge has battery-backed RAM at $6000
+    pub battery: Option<bool>,
+}
+
+impl InesOptions {
+    /// Takes every setting that `other` gives, and keeps the rest
+    pub fn merge(&mut self, other: &InesOptions) {
+        self.prg_banks = other.prg_banks.or(self.prg_banks);
+        self.chr_banks = other.chr_banks.or(self.chr_banks);
+        self.mapper = other.mapper.or(self.mapper);
+        self.mirroring = other.mirroring.or(self.mirroring);
+        self.battery = other.battery.or(self.battery);
+    }
+}
+
+/// Writes a ROM image with an iNES header, or a NES 2.0 header if `nes2` is set. The settings in the program
+/// are overridden by the target's.
+pub fn write(ir: &IR, target: &Target, binary: &[u8], nes2: bool) -> error::Result<Vec<u8>> {
+    let mut options = ir.ines.clone();
+    options.merge(&target.ines);
+    let (prg, chr) = split_rom(target, binary);
+    let prg_banks = bank_count("PRG", prg.len(), PRG_BANK_SIZE, options.prg_banks)?;
+    let chr_banks = bank_count("CHR", chr.len(), CHR_BANK_SIZE, options.chr_banks)?;
+    let mapper = options.mapper.unwrap_or(0);
+    if prg_banks == 0 {
+        return Err(invalid("there's no PRG ROM"));
+    }
+    let (max_banks, max_mapper) = if nes2 { (0xEFF, 0xFFF) } else { (0xFF, 0xFF) };
+    if prg_banks > max_banks || chr_banks > max_banks {
+        return Err(invalid(format!("the header can't hold more than {} banks of each kind", max_banks)));
+    }
+    if mapper > max_mapper {
+        return Err(invalid(format!("mapper {} is above the header's limit of {}", mapper, max_mapper)));
+    }
+
+    let battery = options.battery.unwrap_or(false);
+    let mut flags6 = (mapper as u8 & 0x0F) << 4;
+    match options.mirroring.unwrap_or(Mirroring::Horizontal) {
+        Mirroring::Horizontal => {}
+        Mirroring::Vertical => flags6 |= 0x01,
+        Mirroring::FourScreen => flags6 |= 0x08,
+    }
+    if battery {
+        flags6 |= 0x02;
+    }
+
+    let mut header = vec![0; HEADER_SIZE];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = prg_banks as u8;
+    header[5] = chr_banks as u8;
+    header[6] = flags6;
+    header[7] = mapper as u8 & 0xF0;
+    if nes2 {
+        header[7] |= 0x08;
+        header[8] = (mapper >> 8) as u8;
+        header[9] = ((chr_banks >> 8) << 4) as u8 | (prg_banks >> 8) as u8;
+        // Sizes are written as shifts of 64 bytes, so 7 is 8 KiB
+        if battery {
+            header[10] = 0x70;
+        }
+        if chr_banks == 0 {
+            header[11] = 0x07;
+        }
+    }
+
+    let mut bytes = header;
+    bytes.extend(prg);
+    bytes.extend(chr);
+    Ok(bytes)
+}
+
+/// Splits the laid out image into PRG ROM and CHR ROM. With a layout, the memory area that the CHR segment is
+/// placed in is the CHR ROM, and the other output areas are the PRG ROM in layout order. Without a layout,
+/// the whole image is PRG ROM.
+fn split_rom(target: &Target, binary: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let layout = match target.layout {
+        Some(ref layout) => layout,
+        None => return (binary.to_vec(), Vec::new()),
+    };
+    let chr_area = layout.segment_area(CHR_SEGMENT);
+    let mut prg = Vec::new();
+    let mut chr = Vec::new();
+    let mut offset = 0;
+    for (index, area) in layout.areas.iter().enumerate() {
+        if !area.output {
+            continue;
+        }
+        let bytes = &binary[offset..offset + area.size as usize];
+        offset += area.size as usize;
+        if chr_area == Some(index) {
+            chr.extend_from_slice(bytes);
+        } else {
+            prg.extend_from_slice(bytes);
+        }
+    }
+    (prg, chr)
+}
+
+/// Checks that the ROM data is a whole number of banks, and that it's the number of banks that was given
+fn bank_count(kind: &str, size: usize, bank_size: usize, given: Option<u16>) -> error::Result<usize> {
+    if size % bank_size != 0 {
+        return Err(invalid(format!(
+            "{} ROM is {} bytes, which isn't a whole number of {} KiB banks",
+            kind,
+            size,
+            bank_size / 1024
+        )));
+    }
+    let banks = size / bank_size;
+    match given {
+        Some(given) if given as usize != banks => Err(invalid(format!(
+            "{} ROM has {} banks, but the header gives {}",
+            kind, banks, given
+        ))),
+        _ => Ok(banks),
+    }
+}
+
+fn invalid<M: Into<String>>(message: M) -> error::Error {
+    error::ErrorKind::InvalidRomImage(message.into()).into()
+}
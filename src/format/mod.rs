@@ -0,0 +1,197 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::fmt::Write;
+
+use error;
+use ir::IR;
+use ir::gen::AppendBytes;
+use target::Target;
+
+pub mod cbm;
+pub mod ihex;
+pub mod ines;
+pub mod o65;
+pub mod srec;
+pub mod xex;
+
+/// File format that the assembled program is written in
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Raw bytes, starting at the first block's address or filling out each output area of the layout
+    Binary,
+    /// Relocatable o65 file
+    O65,
+    /// Intel HEX records, which only cover the addresses that have code
+    IntelHex,
+    /// Motorola S-records, which only cover the addresses that have code
+    SRecord,
+    /// NES ROM image with an iNES header
+    Ines,
+    /// NES ROM image with a NES 2.0 header
+    Nes2,
+    /// Commodore program file, which starts with the address that it's loaded at
+    Prg,
+    /// Atari DOS binary file, with a segment for each run of code and for the `.run` and `.init` addresses
+    Xex,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Binary
+    }
+}
+
+impl OutputFormat {
+    pub fn all() -> &'static [OutputFormat] {
+        static ALL: [OutputFormat; 8] = [
+            OutputFormat::Binary,
+            OutputFormat::O65,
+            OutputFormat::IntelHex,
+            OutputFormat::SRecord,
+            OutputFormat::Ines,
+            OutputFormat::Nes2,
+            OutputFormat::Prg,
+            OutputFormat::Xex,
+        ];
+        &ALL
+    }
+
+    /// Name used by the `--format` command line option
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OutputFormat::Binary => "bin",
+            OutputFormat::O65 => "o65",
+            OutputFormat::IntelHex => "ihex",
+            OutputFormat::SRecord => "srec",
+            OutputFormat::Ines => "ines",
+            OutputFormat::Nes2 => "nes2",
+            OutputFormat::Prg => "prg",
+            OutputFormat::Xex => "xex",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        OutputFormat::all()
+            .iter()
+            .find(|format| format.name() == name)
+            .cloned()
+    }
+
+    /// Whether the format needs to know where each address is used, which is recorded while resolving
+    pub fn is_relocatable(&self) -> bool {
+        *self == OutputFormat::O65
+    }
+
+    /// Writes resolved IR in the format. The binary image is laid out by the assembler, which also checks it for
+    /// overlapping code, so it's passed in rather than built again.
+    pub fn write(&self, ir: &IR, target: &Target, binary: Vec<u8>) -> error::Result<Vec<u8>> {
+        Ok(match *self {
+            OutputFormat::Binary => binary,
+            OutputFormat::O65 => o65::O65File::from_ir(ir, target.fill_byte)?.to_bytes(),
+            OutputFormat::IntelHex => ihex::write(&image_segments(ir, target)).into_bytes(),
+            OutputFormat::SRecord => srec::write(&image_segments(ir, target)).into_bytes(),
+            OutputFormat::Ines => ines::write(ir, target, &binary, false)?,
+            OutputFormat::Nes2 => ines::write(ir, target, &binary, true)?,
+            OutputFormat::Prg => cbm::write(ir, target, binary),
+            OutputFormat::Xex => xex::write(ir, target),
+        })
+    }
+}
+
+/// Run of bytes that are loaded at consecutive addresses
+#[derive(Debug, Clone, Eq, PartialEq, new)]
+pub struct ImageSegment {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+}
+
+impl ImageSegment {
+    /// Address just past the last byte
+    pub fn end(&self) -> usize {
+        self.address as usize + self.bytes.len()
+    }
+}
+
+/// Returns the bytes of every block that produces output at the address that it's loaded at, in output order.
+/// Blocks that follow each other are joined into one segment, and gaps between blocks are left out rather than
+/// filled, as are memory areas that don't produce output.
+pub fn image_segments(ir: &IR, target: &Target) -> Vec<ImageSegment> {
+    join_segments(block_segments(ir, target).into_iter().filter_map(|segment| segment).collect())
+}
+
+/// Returns the bytes of each block at the address that it's loaded at, or nothing for a block that doesn't
+/// produce output
+pub fn block_segments(ir: &IR, target: &Target) -> Vec<Option<ImageSegment>> {
+    ir.blocks
+        .iter()
+        .zip(ir.block_outputs())
+        .map(|(block, output)| {
+            let area_output = match target.layout {
+                Some(ref layout) => layout
+                    .segment_area(&block.segment)
+                    .map(|index| layout.areas[index].output)
+                    .unwrap_or(false),
+                None => true,
+            };
+            match block.load_position {
+                Some(address) if output && area_output && block.length > 0 => {
+                    let mut bytes = Vec::new();
+                    block.append_bytes(&mut bytes);
+                    Some(ImageSegment::new(address, bytes))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Joins segments that each start where the one before them ends
+pub fn join_segments(segments: Vec<ImageSegment>) -> Vec<ImageSegment> {
+    let mut joined: Vec<ImageSegment> = Vec::new();
+    for segment in segments {
+        let continues = joined
+            .last()
+            .map(|last| last.end() == segment.address as usize)
+            .unwrap_or(false);
+        if continues {
+            joined.last_mut().unwrap().bytes.extend(segment.bytes);
+        } else {
+            joined.push(segment);
+        }
+    }
+    joined
+}
+
+/// Decodes a record's hex digits, which are two for each byte
+fn decode_hex(digits: &str, line: usize) -> error::Result<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.is_ascii() {
+        return Err(invalid(format!("line {} doesn't have two hex digits for each byte", line)));
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&digits[index..index + 2], 16)
+                .map_err(|_| invalid(format!("line {} has a digit that isn't hex", line)))
+        })
+        .collect()
+}
+
+/// Appends a line of hex digits for the bytes after the record's start code
+fn push_record(text: &mut String, start: &str, bytes: &[u8]) {
+    text.push_str(start);
+    for byte in bytes {
+        write!(text, "{:02X}", byte).unwrap();
+    }
+    text.push('\n');
+}
+
+fn invalid<M: Into<String>>(message: M) -> error::Error {
+    error::ErrorKind::InvalidHexFile(message.into()).into()
+}
@@ -0,0 +1,636 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use ast;
+use error;
+use ir::gen::AppendBytes;
+use ir::{IRBlock, IRChunk, IRConstant, IRData, IRExport, IRFill, IRFillKind, IRImport, IRPlacement, IRRelocationKind,
+         IR};
+use layout::DEFAULT_SEGMENT;
+use object::ObjectFile;
+use src_tag::SrcTag;
+use src_unit::SrcUnits;
+
+/// Bytes that every o65 file starts with: a marker that no 6502 program starts with, the magic number and version 0
+const MAGIC: [u8; 6] = [0x01, 0x00, b'o', b'6', b'5', 0x00];
+
+/// Mode bits for features that aren't supported
+const MODE_65816: u16 = 0x8000;
+const MODE_PAGE_RELOCATION: u16 = 0x4000;
+const MODE_32_BIT: u16 = 0x2000;
+const MODE_CHAIN: u16 = 0x0400;
+
+/// Header option that names the program that wrote the file
+const ASSEMBLER_OPTION: u8 = 2;
+
+const RELOCATE_WORD: u8 = 0x80;
+const RELOCATE_HIGH: u8 = 0x40;
+const RELOCATE_LOW: u8 = 0x20;
+
+/// Segment that an o65 address is in, as numbered in relocation tables and exports
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum O65Segment {
+    /// Address of a name that another file defines
+    Undefined,
+    /// Address that isn't relocated
+    Absolute,
+    Text,
+    Data,
+    Bss,
+    Zero,
+}
+
+impl O65Segment {
+    pub fn id(&self) -> u8 {
+        match *self {
+            O65Segment::Undefined => 0,
+            O65Segment::Absolute => 1,
+            O65Segment::Text => 2,
+            O65Segment::Data => 3,
+            O65Segment::Bss => 4,
+            O65Segment::Zero => 5,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<O65Segment> {
+        match id {
+            0 => Some(O65Segment::Undefined),
+            1 => Some(O65Segment::Absolute),
+            2 => Some(O65Segment::Text),
+            3 => Some(O65Segment::Data),
+            4 => Some(O65Segment::Bss),
+            5 => Some(O65Segment::Zero),
+            _ => None,
+        }
+    }
+
+    /// Returns the o65 segment that a segment's code is written to. The "DATA", "BSS" and "ZEROPAGE" segments go
+    /// in the o65 segments of the same kind, and every other segment goes in text.
+    pub fn for_segment(name: &str) -> O65Segment {
+        match name {
+            "DATA" => O65Segment::Data,
+            "BSS" => O65Segment::Bss,
+            "ZEROPAGE" => O65Segment::Zero,
+            _ => O65Segment::Text,
+        }
+    }
+
+    /// Segment that a file's o65 segment is placed in when it's linked
+    fn segment_name(&self) -> &'static str {
+        match *self {
+            O65Segment::Data => "DATA",
+            O65Segment::Bss => "BSS",
+            O65Segment::Zero => "ZEROPAGE",
+            _ => DEFAULT_SEGMENT,
+        }
+    }
+
+    /// Label at the start of the segment when it's linked, which relocated addresses are relative to. The dot
+    /// keeps it from clashing with names in source code.
+    fn label(&self) -> &'static str {
+        match *self {
+            O65Segment::Data => ".data",
+            O65Segment::Bss => ".bss",
+            O65Segment::Zero => ".zero",
+            _ => ".text",
+        }
+    }
+}
+
+/// Place in the text or data segment that holds an address
+#[derive(Debug, Clone, Eq, PartialEq, new)]
+pub struct O65Relocation {
+    /// Offset of the address from the start of the segment that holds it
+    pub offset: u16,
+    pub kind: IRRelocationKind,
+    /// Segment that the address is in
+    pub segment: O65Segment,
+    /// Index of the undefined name, when the segment is `Undefined`
+    pub undefined: u16,
+}
+
+/// Name that the file defines for other files to use
+#[derive(Debug, Clone, Eq, PartialEq, new)]
+pub struct O65Export {
+    pub name: String,
+    pub segment: O65Segment,
+    pub value: u16,
+}
+
+/// Relocatable program in the o65 format, which 6502 loaders can place at any address. The text and data are
+/// assembled to run at their base addresses, and the relocation tables list every address in them that a loader
+/// has to adjust when it moves a segment.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct O65File {
+    pub mode: u16,
+    pub text_base: u16,
+    pub text: Vec<u8>,
+    pub data_base: u16,
+    pub data: Vec<u8>,
+    pub bss_base: u16,
+    pub bss_length: u16,
+    pub zero_base: u16,
+    pub zero_length: u16,
+    pub stack_size: u16,
+    /// Header options by type
+    pub options: Vec<(u8, Vec<u8>)>,
+    /// Names that the file uses, but doesn't define
+    pub undefined: Vec<String>,
+    pub text_relocations: Vec<O65Relocation>,
+    pub data_relocations: Vec<O65Relocation>,
+    pub exports: Vec<O65Export>,
+}
+
+impl O65File {
+    /// True if the bytes start like an o65 file
+    pub fn is_o65(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    /// Writes resolved IR, which has to be resolved for a relocatable target so that it has relocations. Code in
+    /// each o65 segment must run at the addresses from its first block to its last, and gaps are filled with the
+    /// fill byte. Imports that no unit exports are left for the loader as undefined names.
+    pub fn from_ir(ir: &IR, fill_byte: u8) -> error::Result<O65File> {
+        let mut file = O65File::default();
+        file.options
+            .push((ASSEMBLER_OPTION, b"hassel_asm".to_vec()));
+        for import in &ir.imports {
+            let exported = ir.exports.iter().any(|export| export.name == import.name);
+            if !exported && !file.undefined.contains(&import.name) {
+                file.undefined.push((*import.name).clone());
+            }
+        }
+        for &segment in &[O65Segment::Text, O65Segment::Data, O65Segment::Bss, O65Segment::Zero] {
+            let blocks: Vec<&IRBlock> = ir.blocks
+                .iter()
+                .filter(|block| block.needs_placement() && O65Segment::for_segment(&block.segment) == segment)
+                .collect();
+            let base = blocks
+                .iter()
+                .map(|block| block.position.unwrap())
+                .min()
+                .unwrap_or(0);
+            let end = blocks
+                .iter()
+                .map(|block| block.position.unwrap() as usize + block.length as usize)
+                .max()
+                .unwrap_or(base as usize);
+            if end - base as usize > 0xFFFF {
+                bail!(error::ErrorKind::InvalidO65(format!(
+                    "the {} segment is {} bytes, which is more than 65535",
+                    segment.label(),
+                    end - base as usize
+                )));
+            }
+            let length = (end - base as usize) as u16;
+            match segment {
+                O65Segment::Text | O65Segment::Data => {
+                    let (bytes, relocations) = file.segment_contents(&blocks, base, length, fill_byte);
+                    if segment == O65Segment::Text {
+                        file.text_base = base;
+                        file.text = bytes;
+                        file.text_relocations = relocations;
+                    } else {
+                        file.data_base = base;
+                        file.data = bytes;
+                        file.data_relocations = relocations;
+                    }
+                }
+                _ => {
+                    if let Some(block) = blocks.iter().find(|block| block.has_data()) {
+                        bail!(error::ErrorKind::InvalidO65(format!(
+                            "segment \"{}\" holds data, but the o65 {} segment can only reserve space",
+                            block.segment,
+                            segment.label()
+                        )));
+                    }
+                    if segment == O65Segment::Bss {
+                        file.bss_base = base;
+                        file.bss_length = length;
+                    } else {
+                        file.zero_base = base;
+                        file.zero_length = length;
+                    }
+                }
+            }
+        }
+        for export in &ir.exports {
+            let value = ir.symbols.get(&export.local).cloned().unwrap_or(0);
+            let segment = match export.segment {
+                Some(ref segment) => O65Segment::for_segment(segment),
+                None => O65Segment::Absolute,
+            };
+            file.exports
+                .push(O65Export::new((*export.name).clone(), segment, value as u16));
+        }
+        Ok(file)
+    }
+
+    /// Lays out the bytes of one o65 segment's blocks, along with their relocations in segment order
+    fn segment_contents(
+        &self,
+        blocks: &[&IRBlock],
+        base: u16,
+        length: u16,
+        fill_byte: u8,
+    ) -> (Vec<u8>, Vec<O65Relocation>) {
+        let mut bytes = vec![fill_byte; length as usize];
+        let mut relocations = Vec::new();
+        for block in blocks {
+            let offset = block.position.unwrap() - base;
+            let mut block_bytes = Vec::new();
+            block.append_bytes(&mut block_bytes);
+            bytes[offset as usize..offset as usize + block_bytes.len()].copy_from_slice(&block_bytes);
+            for relocation in &block.relocations {
+                let (segment, undefined) = match relocation.import {
+                    Some(ref name) => {
+                        let index = self.undefined.iter().position(|undefined| undefined == &**name);
+                        (O65Segment::Undefined, index.unwrap() as u16)
+                    }
+                    None => (O65Segment::for_segment(&relocation.segment), 0),
+                };
+                relocations.push(O65Relocation::new(offset + relocation.offset, relocation.kind, segment, undefined));
+            }
+        }
+        relocations.sort_by_key(|relocation| relocation.offset);
+        (bytes, relocations)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let header = [
+            self.mode,
+            self.text_base,
+            self.text.len() as u16,
+            self.data_base,
+            self.data.len() as u16,
+            self.bss_base,
+            self.bss_length,
+            self.zero_base,
+            self.zero_length,
+            self.stack_size,
+        ];
+        for &word in &header {
+            push_word(&mut bytes, word);
+        }
+        for &(kind, ref data) in &self.options {
+            bytes.push(data.len() as u8 + 2);
+            bytes.push(kind);
+            bytes.extend(data);
+        }
+        bytes.push(0);
+        bytes.extend(&self.text);
+        bytes.extend(&self.data);
+
+        push_word(&mut bytes, self.undefined.len() as u16);
+        for name in &self.undefined {
+            push_name(&mut bytes, name);
+        }
+        push_relocations(&mut bytes, &self.text_relocations);
+        push_relocations(&mut bytes, &self.data_relocations);
+        push_word(&mut bytes, self.exports.len() as u16);
+        for export in &self.exports {
+            push_name(&mut bytes, &export.name);
+            bytes.push(export.segment.id());
+            push_word(&mut bytes, export.value);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> error::Result<O65File> {
+        if !O65File::is_o65(bytes) {
+            return Err(invalid("it doesn't start with the o65 marker and version 0"));
+        }
+        let mut input = Input {
+            bytes: bytes,
+            position: MAGIC.len(),
+        };
+        let mut file = O65File::default();
+        file.mode = input.word()?;
+        for &(bit, feature) in &[
+            (MODE_65816, "65816 code"),
+            (MODE_PAGE_RELOCATION, "page-wise relocation"),
+            (MODE_32_BIT, "32-bit sizes"),
+            (MODE_CHAIN, "chained files"),
+        ] {
+            if file.mode & bit != 0 {
+                return Err(invalid(format!("{} aren't supported", feature)));
+            }
+        }
+        file.text_base = input.word()?;
+        let text_length = input.word()?;
+        file.data_base = input.word()?;
+        let data_length = input.word()?;
+        file.bss_base = input.word()?;
+        file.bss_length = input.word()?;
+        file.zero_base = input.word()?;
+        file.zero_length = input.word()?;
+        file.stack_size = input.word()?;
+        loop {
+            let length = input.byte()?;
+            if length == 0 {
+                break;
+            }
+            if length < 2 {
+                return Err(invalid(format!("header option has length {}", length)));
+            }
+            let kind = input.byte()?;
+            file.options
+                .push((kind, input.take(length as usize - 2)?.to_vec()));
+        }
+        file.text = input.take(text_length as usize)?.to_vec();
+        file.data = input.take(data_length as usize)?.to_vec();
+
+        for _ in 0..input.word()? {
+            file.undefined.push(input.name()?);
+        }
+        file.text_relocations = input.relocations(text_length, file.undefined.len())?;
+        file.data_relocations = input.relocations(data_length, file.undefined.len())?;
+        for _ in 0..input.word()? {
+            let name = input.name()?;
+            let segment = input.segment()?;
+            let value = input.word()?;
+            file.exports.push(O65Export::new(name, segment, value));
+        }
+        Ok(file)
+    }
+
+    /// Converts the file into an object file that can be linked, named after the given file name. The text goes
+    /// in the "CODE" segment, and the data, bss and zero page segments go in the segments of the same name, which
+    /// the memory layout has to place. Each segment starts with a label that its relocated addresses refer to.
+    pub fn to_object(&self, name: &str) -> error::Result<ObjectFile> {
+        let tag = SrcTag::new(0, 0);
+        let mut blocks = Vec::new();
+        for &segment in &[O65Segment::Text, O65Segment::Data, O65Segment::Bss, O65Segment::Zero] {
+            let (length, relocations) = match segment {
+                O65Segment::Text => (self.text.len() as u16, &self.text_relocations[..]),
+                O65Segment::Data => (self.data.len() as u16, &self.data_relocations[..]),
+                O65Segment::Bss => (self.bss_length, &[][..]),
+                _ => (self.zero_length, &[][..]),
+            };
+            // Text always gets a block, to hold the exports
+            if segment != O65Segment::Text && length == 0 && !self.refers_to(segment) {
+                continue;
+            }
+            let mut block = IRBlock::new(
+                tag,
+                IRPlacement::Normal,
+                Arc::new(String::new()),
+                Arc::new(segment.segment_name().into()),
+                None,
+                Some(Arc::new(segment.label().into())),
+            );
+            block.chunks = match segment {
+                O65Segment::Text => self.data_chunks(tag, &self.text, relocations)?,
+                O65Segment::Data => self.data_chunks(tag, &self.data, relocations)?,
+                _ => vec![IRChunk::Fill(IRFill::new(tag, IRFillKind::Reserve(length), 0, 0))],
+            };
+            blocks.push(block);
+        }
+
+        let mut exports = Vec::new();
+        for export in &self.exports {
+            let name = Arc::new(export.name.clone());
+            let expr = self.address_expr(tag, export.segment, 0, export.value as i64)?;
+            blocks[0]
+                .chunks
+                .push(IRChunk::Constant(IRConstant::new(tag, Arc::clone(&name), expr)));
+            exports.push(IRExport::new(tag, Arc::clone(&name), name));
+        }
+        let imports = self.undefined
+            .iter()
+            .map(|name| IRImport::new(tag, Arc::new(name.clone()), Arc::new(name.clone()), false))
+            .collect();
+
+        let mut src_units = SrcUnits::new();
+        src_units.push_unit(name.into(), String::new());
+        let ir = IR::new(blocks, exports, imports, BTreeMap::new());
+        Ok(ObjectFile::new(&src_units, ir, Vec::new()))
+    }
+
+    /// Whether any relocation or export refers to the segment
+    fn refers_to(&self, segment: O65Segment) -> bool {
+        self.text_relocations
+            .iter()
+            .chain(&self.data_relocations)
+            .any(|relocation| relocation.segment == segment)
+            || self.exports.iter().any(|export| export.segment == segment)
+    }
+
+    /// Turns the bytes of the text or data segment into data chunks, with an expression for each address that
+    /// refers to the label that its segment starts at
+    fn data_chunks(&self, tag: SrcTag, bytes: &[u8], relocations: &[O65Relocation]) -> error::Result<Vec<IRChunk>> {
+        let mut chunks = Vec::new();
+        let mut plain: Vec<ast::Expr> = Vec::new();
+        let mut offset = 0;
+        for relocation in relocations {
+            let width = if relocation.kind == IRRelocationKind::Word { 2 } else { 1 };
+            let start = relocation.offset as usize;
+            if start < offset || start + width > bytes.len() {
+                return Err(invalid(format!("relocation at offset ${:04X} is out of place", start)));
+            }
+            plain.extend(bytes[offset..start].iter().map(|&byte| number(tag, byte as i64)));
+            let expr = match relocation.kind {
+                IRRelocationKind::Word => {
+                    let value = bytes[start] as i64 | (bytes[start + 1] as i64) << 8;
+                    self.address_expr(tag, relocation.segment, relocation.undefined, value)?
+                }
+                IRRelocationKind::Low => {
+                    let value = self.address_expr(tag, relocation.segment, relocation.undefined, bytes[start] as i64)?;
+                    ast::Expr::Unary(tag, ast::UnaryOperator::LowByte, Box::new(value))
+                }
+                IRRelocationKind::High(low) => {
+                    let address = (bytes[start] as i64) << 8 | low as i64;
+                    let value = self.address_expr(tag, relocation.segment, relocation.undefined, address)?;
+                    ast::Expr::Unary(tag, ast::UnaryOperator::HighByte, Box::new(value))
+                }
+            };
+            if !plain.is_empty() {
+                chunks.push(data_chunk(tag, 1, ::std::mem::replace(&mut plain, Vec::new())));
+            }
+            chunks.push(data_chunk(tag, width, vec![expr]));
+            offset = start + width;
+        }
+        plain.extend(bytes[offset..].iter().map(|&byte| number(tag, byte as i64)));
+        if !plain.is_empty() {
+            chunks.push(data_chunk(tag, 1, plain));
+        }
+        Ok(chunks)
+    }
+
+    /// Expression for an address in a segment, relative to the label that the segment starts at once linked
+    fn address_expr(&self, tag: SrcTag, segment: O65Segment, undefined: u16, value: i64) -> error::Result<ast::Expr> {
+        let (name, base) = match segment {
+            O65Segment::Absolute => return Ok(number(tag, value)),
+            O65Segment::Undefined => match self.undefined.get(undefined as usize) {
+                Some(name) => (name.clone(), 0),
+                None => return Err(invalid(format!("undefined name {} doesn't exist", undefined))),
+            },
+            O65Segment::Text => (segment.label().into(), self.text_base),
+            O65Segment::Data => (segment.label().into(), self.data_base),
+            O65Segment::Bss => (segment.label().into(), self.bss_base),
+            O65Segment::Zero => (segment.label().into(), self.zero_base),
+        };
+        let start = ast::Expr::Name(tag, Arc::new(name));
+        let offset = value - base as i64;
+        Ok(match offset {
+            0 => start,
+            _ if offset > 0 => ast::Expr::Binary(
+                tag,
+                ast::BinaryOperator::Add,
+                Box::new(start),
+                Box::new(number(tag, offset)),
+            ),
+            _ => ast::Expr::Binary(
+                tag,
+                ast::BinaryOperator::Subtract,
+                Box::new(start),
+                Box::new(number(tag, -offset)),
+            ),
+        })
+    }
+}
+
+fn number(tag: SrcTag, value: i64) -> ast::Expr {
+    ast::Expr::Number(tag, ast::Number::from_value(value as usize))
+}
+
+fn data_chunk(tag: SrcTag, width: usize, exprs: Vec<ast::Expr>) -> IRChunk {
+    IRChunk::Data(IRData {
+        tag: tag,
+        width: width,
+        exprs: exprs,
+        bytes: Vec::new(),
+    })
+}
+
+fn invalid<M: Into<String>>(message: M) -> error::Error {
+    error::ErrorKind::InvalidO65(message.into()).into()
+}
+
+fn push_word(bytes: &mut Vec<u8>, word: u16) {
+    bytes.push(word as u8);
+    bytes.push((word >> 8) as u8);
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    bytes.extend(name.as_bytes());
+    bytes.push(0);
+}
+
+/// Writes a relocation table. Each entry starts with its distance from the previous entry, which starts one byte
+/// before the segment, and 255 skips ahead 254 bytes without an entry.
+fn push_relocations(bytes: &mut Vec<u8>, relocations: &[O65Relocation]) {
+    let mut position = -1i32;
+    for relocation in relocations {
+        let mut distance = relocation.offset as i32 - position;
+        while distance > 254 {
+            bytes.push(255);
+            distance -= 254;
+        }
+        bytes.push(distance as u8);
+        position = relocation.offset as i32;
+
+        let kind = match relocation.kind {
+            IRRelocationKind::Word => RELOCATE_WORD,
+            IRRelocationKind::High(_) => RELOCATE_HIGH,
+            IRRelocationKind::Low => RELOCATE_LOW,
+        };
+        bytes.push(kind | relocation.segment.id());
+        if relocation.segment == O65Segment::Undefined {
+            push_word(bytes, relocation.undefined);
+        }
+        if let IRRelocationKind::High(low) = relocation.kind {
+            bytes.push(low);
+        }
+    }
+    bytes.push(0);
+}
+
+struct Input<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Input<'a> {
+    fn take(&mut self, count: usize) -> error::Result<&'a [u8]> {
+        if self.position + count > self.bytes.len() {
+            return Err(invalid("it ends early"));
+        }
+        self.position += count;
+        Ok(&self.bytes[self.position - count..self.position])
+    }
+
+    fn byte(&mut self) -> error::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> error::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(bytes[0] as u16 | (bytes[1] as u16) << 8)
+    }
+
+    /// Reads a name that ends with a zero byte
+    fn name(&mut self) -> error::Result<String> {
+        let length = match self.bytes[self.position..].iter().position(|&byte| byte == 0) {
+            Some(length) => length,
+            None => return Err(invalid("it ends early")),
+        };
+        let name = String::from_utf8_lossy(self.take(length)?).into_owned();
+        self.position += 1;
+        Ok(name)
+    }
+
+    fn segment(&mut self) -> error::Result<O65Segment> {
+        let id = self.byte()?;
+        O65Segment::from_id(id).ok_or_else(|| invalid(format!("unknown segment {}", id)))
+    }
+
+    fn relocations(&mut self, segment_length: u16, undefined_count: usize) -> error::Result<Vec<O65Relocation>> {
+        let mut relocations = Vec::new();
+        let mut position = -1i32;
+        loop {
+            let mut distance = self.byte()?;
+            while distance == 255 {
+                position += 254;
+                distance = self.byte()?;
+            }
+            if distance == 0 {
+                return Ok(relocations);
+            }
+            position += distance as i32;
+            if position >= segment_length as i32 {
+                return Err(invalid(format!("relocation at offset ${:04X} is past its segment", position)));
+            }
+
+            let kind_and_segment = self.byte()?;
+            let segment = O65Segment::from_id(kind_and_segment & 0x1F)
+                .ok_or_else(|| invalid(format!("unknown segment {}", kind_and_segment & 0x1F)))?;
+            let undefined = if segment == O65Segment::Undefined {
+                let index = self.word()?;
+                if index as usize >= undefined_count {
+                    return Err(invalid(format!("undefined name {} doesn't exist", index)));
+                }
+                index
+            } else {
+                0
+            };
+            let kind = match kind_and_segment & 0xE0 {
+                RELOCATE_WORD => IRRelocationKind::Word,
+                RELOCATE_HIGH => IRRelocationKind::High(self.byte()?),
+                RELOCATE_LOW => IRRelocationKind::Low,
+                kind => return Err(invalid(format!("relocation type ${:02X} isn't supported", kind))),
+            };
+            relocations.push(O65Relocation::new(position as u16, kind, segment, undefined));
+        }
+    }
+}
@@ -0,0 +1,91 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+//! Motorola S-records, which is a line for each record: `S` and the record type, then hex digits for the number of
+//! bytes that follow, the address, the data, and a checksum that makes the bytes add up to $FF.
+
+use error;
+use format::{decode_hex, invalid, join_segments, push_record, ImageSegment};
+
+/// Most data bytes that a record is written with
+const RECORD_SIZE: usize = 16;
+
+/// Writes a header, an S1 record for up to every 16 bytes of each segment, the record count, and an S9 record that
+/// starts the program at the first segment
+pub fn write(segments: &[ImageSegment]) -> String {
+    let mut text = String::new();
+    push(&mut text, '0', 0, &[]);
+    let mut count = 0;
+    for segment in segments {
+        for (index, data) in segment.bytes.chunks(RECORD_SIZE).enumerate() {
+            let address = segment.address as usize + index * RECORD_SIZE;
+            push(&mut text, '1', address as u16, data);
+            count += 1;
+        }
+    }
+    if count <= 0xFFFF {
+        push(&mut text, '5', count as u16, &[]);
+    }
+    let start = segments.first().map(|segment| segment.address).unwrap_or(0);
+    push(&mut text, '9', start, &[]);
+    text
+}
+
+/// Writes a record with a 16-bit address
+fn push(text: &mut String, kind: char, address: u16, data: &[u8]) {
+    let mut record = vec![data.len() as u8 + 3, (address >> 8) as u8, address as u8];
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    record.push(!sum);
+    push_record(text, &format!("S{}", kind), &record);
+}
+
+/// Reads the S1, S2 and S3 data records up to the termination record, joining records that follow each other.
+/// The data has to be within the first 64KB.
+pub fn read(text: &str) -> error::Result<Vec<ImageSegment>> {
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let kind = match (line.chars().next(), line.chars().nth(1)) {
+            (Some('S'), Some(kind)) if kind.is_digit(10) && kind != '4' => kind,
+            _ => return Err(invalid(format!("line {} doesn't start with a record type", number))),
+        };
+        let address_length = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            _ => 4,
+        };
+        let bytes = decode_hex(&line[2..], number)?;
+        if bytes.len() < address_length + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(invalid(format!("line {} has the wrong length", number)));
+        }
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xFF {
+            return Err(invalid(format!("line {} has the wrong checksum", number)));
+        }
+        let address = bytes[1..address_length + 1]
+            .iter()
+            .fold(0usize, |address, &byte| address << 8 | byte as usize);
+        let data = &bytes[address_length + 1..bytes.len() - 1];
+        match kind {
+            '1' | '2' | '3' => {
+                if address + data.len() > 0x10000 {
+                    return Err(invalid(format!("line {} has data past $FFFF", number)));
+                }
+                records.push(ImageSegment::new(address as u16, data.to_vec()));
+            }
+            '7' | '8' | '9' => return Ok(join_segments(records)),
+            _ => {}
+        }
+    }
+    Err(invalid("it has no termination record"))
+}
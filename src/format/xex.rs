@@ -0,0 +1,77 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use format::{self, ImageSegment};
+use ir::{IRChunk, IRRunKind, IR};
+use target::Target;
+
+/// Vector that DOS jumps through once the file is loaded
+pub const RUNAD: u16 = 0x02E0;
+/// Vector that DOS calls through as soon as it's loaded
+pub const INITAD: u16 = 0x02E2;
+
+/// Writes an Atari DOS binary file. Each run of blocks at consecutive addresses is written as a segment with its
+/// first and last address. An `.init` address follows the run that it's in, so that DOS calls it before loading
+/// the rest of the file, and the `.run` addresses come last.
+pub fn write(ir: &IR, target: &Target) -> Vec<u8> {
+    // Each run of blocks, with the `.init` addresses that follow it
+    let mut runs: Vec<(ImageSegment, Vec<u16>)> = Vec::new();
+    // `.init` addresses that come before any code
+    let mut leading_inits = Vec::new();
+    let mut run_addresses = Vec::new();
+    for (block, segment) in ir.blocks.iter().zip(format::block_segments(ir, target)) {
+        if let Some(segment) = segment {
+            let continues = runs.last()
+                .map(|&(ref last, _)| last.end() == segment.address as usize)
+                .unwrap_or(false);
+            match runs.last_mut() {
+                Some(&mut (ref mut last, ref inits)) if continues && inits.is_empty() => {
+                    last.bytes.extend(segment.bytes)
+                }
+                _ => runs.push((segment, Vec::new())),
+            }
+        }
+        for chunk in &block.chunks {
+            if let IRChunk::RunAddress(ref run_address) = *chunk {
+                match run_address.kind {
+                    IRRunKind::Run => run_addresses.push(run_address.address),
+                    IRRunKind::Init => match runs.last_mut() {
+                        Some(&mut (_, ref mut inits)) => inits.push(run_address.address),
+                        None => leading_inits.push(run_address.address),
+                    },
+                }
+            }
+        }
+    }
+
+    let mut bytes = vec![0xFF, 0xFF];
+    for &address in &leading_inits {
+        push_vector(&mut bytes, INITAD, address);
+    }
+    for &(ref segment, ref inits) in &runs {
+        push_segment(&mut bytes, segment);
+        for &address in inits {
+            push_vector(&mut bytes, INITAD, address);
+        }
+    }
+    for &address in &run_addresses {
+        push_vector(&mut bytes, RUNAD, address);
+    }
+    bytes
+}
+
+fn push_vector(bytes: &mut Vec<u8>, vector: u16, address: u16) {
+    push_segment(bytes, &ImageSegment::new(vector, vec![address as u8, (address >> 8) as u8]));
+}
+
+fn push_segment(bytes: &mut Vec<u8>, segment: &ImageSegment) {
+    let last = (segment.end() - 1) as u16;
+    bytes.extend(&[segment.address as u8, (segment.address >> 8) as u8, last as u8, (last >> 8) as u8]);
+    bytes.extend(&segment.bytes);
+}
@@ -0,0 +1,141 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ast;
+use diagnostic::{Diagnostic, DiagnosticCode};
+use ir::lookup_candidates;
+use src_tag::SrcTag;
+
+/// Name of the hidden member that holds a `.struct`'s size
+pub const SIZE_MEMBER: &str = ".sizeof";
+
+/// Supplies the values of names and of `*` while an expression is evaluated
+pub trait Symbols {
+    fn symbol(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic>;
+    fn program_counter(&mut self, tag: SrcTag) -> Result<i64, Diagnostic>;
+    fn is_defined(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<bool, Diagnostic>;
+    fn size_of(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic>;
+}
+
+/// Qualified name of the size member of the `.struct` with the given name
+pub fn size_member(name: &str) -> Arc<String> {
+    Arc::new(format!("{}::{}", name, SIZE_MEMBER))
+}
+
+/// Symbols known before any addresses are assigned, which are constants defined from numbers alone
+pub struct ConstantSymbols<'a> {
+    /// Values of constants by qualified name
+    pub constants: &'a HashMap<Arc<String>, i64>,
+    /// Every label and constant defined so far, so that an inner name hides an outer constant
+    pub defined: &'a HashMap<Arc<String>, SrcTag>,
+    /// Scope that names are looked up from
+    pub scope: &'a str,
+}
+
+impl<'a> Symbols for ConstantSymbols<'a> {
+    fn symbol(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        lookup_candidates(self.scope, name)
+            .into_iter()
+            .find(|candidate| self.defined.contains_key(candidate))
+            .and_then(|candidate| self.constants.get(&candidate).cloned())
+            .ok_or_else(|| not_constant(tag))
+    }
+
+    fn program_counter(&mut self, tag: SrcTag) -> Result<i64, Diagnostic> {
+        Err(not_constant(tag))
+    }
+
+    /// Names that aren't defined yet might still be defined further on, so only a defined name gives an answer
+    fn is_defined(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<bool, Diagnostic> {
+        if lookup_candidates(self.scope, name)
+            .iter()
+            .any(|candidate| self.defined.contains_key(candidate))
+        {
+            Ok(true)
+        } else {
+            Err(not_constant(tag))
+        }
+    }
+
+    fn size_of(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        self.symbol(tag, &size_member(name))
+    }
+}
+
+fn not_constant(tag: SrcTag) -> Diagnostic {
+    Diagnostic::error(DiagnosticCode::UnknownLabel, "value isn't known until addresses are assigned").with_span(tag)
+}
+
+pub fn evaluate<S: Symbols>(expr: &ast::Expr, symbols: &mut S) -> Result<i64, Diagnostic> {
+    use ast::BinaryOperator::*;
+    use ast::Function::*;
+    use ast::UnaryOperator::*;
+    match *expr {
+        ast::Expr::Number(_, number) => Ok(number.value() as i64),
+        ast::Expr::Name(tag, ref name) => symbols.symbol(tag, name),
+        ast::Expr::ProgramCounter(tag) => symbols.program_counter(tag),
+        ast::Expr::SizeOf(tag, ref name) => symbols.size_of(tag, name),
+        ast::Expr::Defined(tag, ref name) => Ok(symbols.is_defined(tag, name)? as i64),
+        ast::Expr::Call(_, function, ref arguments) => {
+            let mut values = Vec::with_capacity(arguments.len());
+            for argument in arguments {
+                values.push(evaluate(argument, symbols)?);
+            }
+            let result = match function {
+                Min => values.into_iter().min(),
+                Max => values.into_iter().max(),
+            };
+            // The grammar requires at least one argument
+            Ok(result.unwrap())
+        }
+        ast::Expr::Unary(_, operator, ref operand) => {
+            let value = evaluate(operand, symbols)?;
+            Ok(match operator {
+                Negate => value.wrapping_neg(),
+                Complement => !value,
+                LogicalNot => (value == 0) as i64,
+                LowByte => value & 0xFF,
+                HighByte => (value >> 8) & 0xFF,
+                BankByte => (value >> 16) & 0xFF,
+            })
+        }
+        ast::Expr::Binary(tag, operator, ref left, ref right) => {
+            let left = evaluate(left, symbols)?;
+            let right = evaluate(right, symbols)?;
+            Ok(match operator {
+                Multiply => left.wrapping_mul(right),
+                Divide | Modulo if right == 0 => {
+                    return Err(Diagnostic::error(DiagnosticCode::DivisionByZero, "division by zero").with_span(tag));
+                }
+                Divide => left.wrapping_div(right),
+                Modulo => left.wrapping_rem(right),
+                Add => left.wrapping_add(right),
+                Subtract => left.wrapping_sub(right),
+                ShiftLeft if right < 0 || right > 63 => 0,
+                ShiftLeft => left << right,
+                ShiftRight if right < 0 || right > 63 => if left < 0 { -1 } else { 0 },
+                ShiftRight => left >> right,
+                BitAnd => left & right,
+                BitXor => left ^ right,
+                BitOr => left | right,
+                Equal => (left == right) as i64,
+                NotEqual => (left != right) as i64,
+                Less => (left < right) as i64,
+                LessEqual => (left <= right) as i64,
+                Greater => (left > right) as i64,
+                GreaterEqual => (left >= right) as i64,
+                LogicalAnd => (left != 0 && right != 0) as i64,
+                LogicalOr => (left != 0 || right != 0) as i64,
+            })
+        }
+    }
+}
@@ -9,13 +9,19 @@
 
 use hassel_lib6502::{OpAddressMode, OpClass, OpCode, OpParam};
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use ast;
-use error;
-use error::ErrorKind::AssemblerError;
-use ir::{IRBlock, IRChunk, IROp, IRParam, IR};
+use diagnostic::{self, Diagnostic, DiagnosticCode, Severity};
+use error::{Warning, WarningConfig};
+use format::ines::{InesOptions, Mirroring};
+use ir::{IRAssert, IRBlock, IRChunk, IRConstant, IRData, IRExport, IRFill, IRFillKind, IRImport, IROp, IRParam,
+         IRPlacement, IRPrint, IRRelocation, IRRelocationKind, IRRemovable, IRRunAddress, IRRunKind, IR};
+use ir::eval::{self, ConstantSymbols, Symbols};
+use ir::{lookup_candidates, qualify, unlinked_name};
+use layout::{MemoryLayout, DEFAULT_SEGMENT};
 use src_tag::SrcTag;
+use target::{Cpu, Target};
 
 pub trait AppendBytes {
     fn append_bytes(&self, bytes: &mut Vec<u8>);
@@ -33,11 +39,20 @@ impl AppendBytes for IRChunk {
     fn append_bytes(&self, bytes: &mut Vec<u8>) {
         match *self {
             IRChunk::Op(ref op) => op.append_bytes(bytes),
-            IRChunk::Bytes(ref val) => bytes.extend(val),
+            IRChunk::Data(ref data) => bytes.extend(&data.bytes),
             IRChunk::Vector(_, _, val) => {
                 bytes.push(val as u8);
                 bytes.push((val >> 8) as u8);
             }
+            IRChunk::PointerCheck(_)
+            | IRChunk::Assert(_)
+            | IRChunk::Constant(_)
+            | IRChunk::Print(_)
+            | IRChunk::RunAddress(_) => {}
+            IRChunk::Fill(ref fill) => {
+                let end = bytes.len() + fill.length as usize;
+                bytes.resize(end, fill.value);
+            }
         }
     }
 }
@@ -61,16 +76,23 @@ impl AppendBytes for IROp {
 }
 
 trait ResolveLength {
-    fn resolve_length(&mut self) -> error::Result<()>;
+    fn resolve_length(&mut self, position: usize) -> Result<(), Diagnostic>;
 }
 
 impl ResolveLength for IRBlock {
-    fn resolve_length(&mut self) -> error::Result<()> {
-        let length = self.chunks
-            .iter()
-            .fold(0usize, |acc, chunk| acc + chunk.len());
+    fn resolve_length(&mut self, block_position: usize) -> Result<(), Diagnostic> {
+        let mut length = 0usize;
+        for chunk in &mut self.chunks {
+            if let IRChunk::Fill(ref mut fill) = *chunk {
+                fill.resolve_length(block_position + length)?;
+            }
+            length += chunk.len();
+        }
         if length > 0xFFFF {
-            Err(error::ErrorKind::SrcUnitError("assembly won't fit in 65535 bytes".into()).into())
+            Err(Diagnostic::error(
+                DiagnosticCode::ProgramTooLarge,
+                "assembly won't fit in 65535 bytes",
+            ))
         } else {
             self.length = length as u16;
             Ok(())
@@ -78,39 +100,375 @@ impl ResolveLength for IRBlock {
     }
 }
 
+impl ResolveLength for IRFill {
+    fn resolve_length(&mut self, position: usize) -> Result<(), Diagnostic> {
+        match self.kind {
+            IRFillKind::PadTo(address) => {
+                if position > address as usize {
+                    return Err(error_at(
+                        DiagnosticCode::PadBackwards,
+                        self.tag,
+                        format!("can't pad to ${:04X}; already at ${:04X}", address, position),
+                    ));
+                }
+                self.length = (address as usize - position) as u16;
+            }
+            IRFillKind::Align(alignment) => {
+                let alignment = alignment as usize;
+                self.length = ((alignment - position % alignment) % alignment) as u16;
+            }
+            IRFillKind::Repeat(count) | IRFillKind::Reserve(count) => self.length = count,
+        }
+        Ok(())
+    }
+}
+
+/// Constant definitions along with the address, scope and segment they were defined in
+type PlacedConstants = HashMap<Arc<String>, (IRConstant, u16, Arc<String>, Arc<String>)>;
+
+/// What a relocated address is relative to
+#[derive(Clone, PartialEq)]
+enum RelocationBase {
+    Segment(Arc<String>),
+    /// Import that no unit exports, which whatever loads the output has to supply
+    Import(Arc<String>),
+}
+
+/// State shared by every chunk while parameters are being resolved
+struct ResolveContext<'a> {
+    cpu: Cpu,
+    lookup_table: &'a HashMap<Arc<String>, u16>,
+    constants: &'a PlacedConstants,
+    /// Number of bytes in each scope, including the scopes nested in it
+    scope_sizes: &'a HashMap<Arc<String>, i64>,
+    /// Segment that each label is in, which relocations refer to
+    label_segments: &'a HashMap<Arc<String>, Arc<String>>,
+    /// Whether to record relocations
+    relocatable: bool,
+    /// Scope of the chunk being resolved, which names are looked up from
+    scope: Arc<String>,
+    /// Segment and address of the block being resolved
+    segment: Arc<String>,
+    block_position: u16,
+    /// Relocations found in the block being resolved
+    relocations: Vec<IRRelocation>,
+    constant_values: HashMap<Arc<String>, i64>,
+    /// Constants that are currently being evaluated, to catch definitions that refer to themselves
+    evaluating: Vec<Arc<String>>,
+    used_labels: HashSet<Arc<String>>,
+    /// Names of the imports that no unit exports, by local name. They're reported where they're imported rather
+    /// than where they're used, unless the output is relocatable and can leave them undefined.
+    undefined_imports: HashMap<Arc<String>, Arc<String>>,
+    /// Whether a lookup failed on an undefined import since the flag was last cleared
+    used_undefined_import: bool,
+    /// Warnings and `.print` messages
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> ResolveContext<'a> {
+    fn new(
+        target: &Target,
+        lookup_table: &'a HashMap<Arc<String>, u16>,
+        constants: &'a PlacedConstants,
+        scope_sizes: &'a HashMap<Arc<String>, i64>,
+        label_segments: &'a HashMap<Arc<String>, Arc<String>>,
+    ) -> ResolveContext<'a> {
+        ResolveContext {
+            cpu: target.cpu,
+            lookup_table: lookup_table,
+            constants: constants,
+            scope_sizes: scope_sizes,
+            label_segments: label_segments,
+            relocatable: target.format.is_relocatable(),
+            scope: Arc::new(String::new()),
+            segment: Arc::new(String::new()),
+            block_position: 0,
+            relocations: Vec::new(),
+            constant_values: HashMap::new(),
+            evaluating: Vec::new(),
+            used_labels: HashSet::new(),
+            undefined_imports: HashMap::new(),
+            used_undefined_import: false,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn lookup(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<u16, Diagnostic> {
+        for candidate in lookup_candidates(&self.scope, name) {
+            if let Some(position) = self.lookup_table.get(&candidate) {
+                self.used_labels.insert(candidate);
+                return Ok(*position);
+            }
+        }
+        self.undefined_name(tag, name).map(|value| value as u16)
+    }
+
+    fn lookup_symbol(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        for candidate in lookup_candidates(&self.scope, name) {
+            if self.lookup_table.contains_key(&candidate) {
+                self.used_labels.insert(Arc::clone(&candidate));
+                return Ok(self.lookup_table[&candidate] as i64);
+            }
+            if self.constants.contains_key(&candidate) {
+                return self.constant_value(&candidate);
+            }
+        }
+        self.undefined_name(tag, name)
+    }
+
+    /// Looks up a name that isn't defined. An undefined import is 0 in relocatable output, where it's relocated
+    /// instead, and otherwise its error has already been reported.
+    fn undefined_name(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        if self.undefined_import(name).is_some() {
+            if self.relocatable {
+                return Ok(0);
+            }
+            self.used_undefined_import = true;
+        }
+        Err(unknown_label(tag, name))
+    }
+
+    /// Returns the name of the undefined import that a name refers to, if it refers to one
+    fn undefined_import(&self, name: &Arc<String>) -> Option<Arc<String>> {
+        lookup_candidates(&self.scope, name)
+            .iter()
+            .filter_map(|candidate| self.undefined_imports.get(candidate))
+            .next()
+            .cloned()
+    }
+
+    fn is_defined(&self, name: &Arc<String>) -> bool {
+        lookup_candidates(&self.scope, name)
+            .iter()
+            .any(|candidate| self.lookup_table.contains_key(candidate) || self.constants.contains_key(candidate))
+    }
+
+    /// Looks up the size of a `.struct`, or else of a scope
+    fn size_of(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        for candidate in lookup_candidates(&self.scope, name) {
+            let member = eval::size_member(&candidate);
+            if self.constants.contains_key(&member) {
+                return self.constant_value(&member);
+            }
+            if let Some(size) = self.scope_sizes.get(&candidate) {
+                return Ok(*size);
+            }
+        }
+        Err(error_at(
+            DiagnosticCode::UnknownLabel,
+            tag,
+            format!("unknown struct or scope: \"{}\"", name),
+        ))
+    }
+
+    /// Evaluates a constant by its qualified name, in the scope that it was defined in
+    fn constant_value(&mut self, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        if let Some(value) = self.constant_values.get(name) {
+            return Ok(*value);
+        }
+
+        let constants = self.constants;
+        let &(ref constant, position, ref scope, _) = &constants[name];
+        if self.evaluating.contains(name) {
+            return Err(error_at(
+                DiagnosticCode::CircularDefinition,
+                constant.tag,
+                format!("constant \"{}\" is defined in terms of itself", name),
+            ));
+        }
+        self.evaluating.push(Arc::clone(name));
+        let outer_scope = ::std::mem::replace(&mut self.scope, Arc::clone(scope));
+        let value = self.evaluate(&constant.expr, position);
+        self.scope = outer_scope;
+        self.evaluating.pop();
+
+        let value = value?;
+        self.constant_values.insert(Arc::clone(name), value);
+        Ok(value)
+    }
+
+    /// Evaluates an expression that appears at the given address, which `*` refers to
+    fn evaluate(&mut self, expr: &ast::Expr, position: u16) -> Result<i64, Diagnostic> {
+        eval::evaluate(
+            expr,
+            &mut PositionedSymbols {
+                context: self,
+                position: position,
+            },
+        )
+    }
+
+    fn warn<M: Into<String>>(&mut self, warning: Warning, tag: SrcTag, message: M) {
+        self.diagnostics
+            .push(Diagnostic::warning(warning, message).with_span(tag));
+    }
+
+    /// Records a relocation for a value of the given width at the given address, if the value is an address or
+    /// a byte of one. Only an address plus or minus a number, or its low or high byte, can be relocated.
+    fn relocate(&mut self, expr: &ast::Expr, position: u16, width: usize) -> Result<(), Diagnostic> {
+        if !self.relocatable {
+            return Ok(());
+        }
+        let (operand, operator) = match *expr {
+            ast::Expr::Unary(_, operator, ref operand) if width == 1 => match operator {
+                ast::UnaryOperator::LowByte | ast::UnaryOperator::HighByte => (&**operand, Some(operator)),
+                _ => (expr, None),
+            },
+            _ => (expr, None),
+        };
+        let base = match self.relocation_base(operand)? {
+            Some(base) => base,
+            None => return Ok(()),
+        };
+        let kind = match operator {
+            _ if width == 2 => IRRelocationKind::Word,
+            Some(ast::UnaryOperator::HighByte) => IRRelocationKind::High(self.evaluate(operand, position)? as u8),
+            _ => IRRelocationKind::Low,
+        };
+        let offset = position.wrapping_sub(self.block_position);
+        self.relocations.push(match base {
+            RelocationBase::Segment(segment) => IRRelocation::new(offset, kind, segment),
+            RelocationBase::Import(name) => {
+                let mut relocation = IRRelocation::new(offset, kind, Arc::clone(&self.segment));
+                relocation.import = Some(name);
+                relocation
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns the segment or undefined import that an expression's value is an address in, or `None` if the value
+    /// doesn't depend on where segments are placed
+    fn relocation_base(&mut self, expr: &ast::Expr) -> Result<Option<RelocationBase>, Diagnostic> {
+        use ast::BinaryOperator::*;
+        Ok(match *expr {
+            ast::Expr::Number(..) | ast::Expr::SizeOf(..) | ast::Expr::Defined(..) => None,
+            ast::Expr::ProgramCounter(_) => Some(RelocationBase::Segment(Arc::clone(&self.segment))),
+            ast::Expr::Name(_, ref name) => self.symbol_base(name)?,
+            ast::Expr::Unary(tag, _, ref operand) => {
+                if self.relocation_base(operand)?.is_some() {
+                    return Err(not_relocatable(tag));
+                }
+                None
+            }
+            ast::Expr::Call(tag, _, ref arguments) => {
+                for argument in arguments {
+                    if self.relocation_base(argument)?.is_some() {
+                        return Err(not_relocatable(tag));
+                    }
+                }
+                None
+            }
+            ast::Expr::Binary(tag, operator, ref left, ref right) => {
+                match (operator, self.relocation_base(left)?, self.relocation_base(right)?) {
+                    (_, None, None) => None,
+                    (Add, Some(base), None) | (Add, None, Some(base)) | (Subtract, Some(base), None) => Some(base),
+                    // The distance between two addresses in the same segment doesn't change
+                    (Subtract, Some(left), Some(right)) if left == right => None,
+                    _ => return Err(not_relocatable(tag)),
+                }
+            }
+        })
+    }
+
+    /// Looks up the segment of a label, or of the address that a constant is defined as, or the undefined import
+    /// that a name refers to. Unknown names have already been reported while evaluating.
+    fn symbol_base(&mut self, name: &Arc<String>) -> Result<Option<RelocationBase>, Diagnostic> {
+        for candidate in lookup_candidates(&self.scope, name) {
+            if let Some(segment) = self.label_segments.get(&candidate) {
+                return Ok(Some(RelocationBase::Segment(Arc::clone(segment))));
+            }
+            if let Some(&(ref constant, _, ref scope, ref segment)) = self.constants.get(&candidate) {
+                let outer_scope = ::std::mem::replace(&mut self.scope, Arc::clone(scope));
+                let outer_segment = ::std::mem::replace(&mut self.segment, Arc::clone(segment));
+                let base = self.relocation_base(&constant.expr);
+                self.scope = outer_scope;
+                self.segment = outer_segment;
+                return base;
+            }
+        }
+        Ok(self.undefined_import(name).map(RelocationBase::Import))
+    }
+}
+
+struct PositionedSymbols<'c, 'a: 'c> {
+    context: &'c mut ResolveContext<'a>,
+    position: u16,
+}
+
+impl<'c, 'a> Symbols for PositionedSymbols<'c, 'a> {
+    fn symbol(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        self.context.lookup_symbol(tag, name)
+    }
+
+    fn program_counter(&mut self, _tag: SrcTag) -> Result<i64, Diagnostic> {
+        Ok(self.position as i64)
+    }
+
+    fn is_defined(&mut self, _tag: SrcTag, name: &Arc<String>) -> Result<bool, Diagnostic> {
+        Ok(self.context.is_defined(name))
+    }
+
+    fn size_of(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<i64, Diagnostic> {
+        self.context.size_of(tag, name)
+    }
+}
+
 trait ResolveParameters {
-    fn resolve_parameters(&mut self, position: u16, lookup_table: &HashMap<Arc<String>, u16>) -> error::Result<()>;
+    fn resolve_parameters(&mut self, position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic>;
 }
 
-impl ResolveParameters for IRBlock {
-    fn resolve_parameters(
-        &mut self,
-        block_position: u16,
-        lookup_table: &HashMap<Arc<String>, u16>,
-    ) -> error::Result<()> {
+impl IRBlock {
+    /// Resolves every chunk in the block, collecting a diagnostic for each chunk that fails
+    fn resolve_chunks(&mut self, block_position: u16, context: &mut ResolveContext, diagnostics: &mut Vec<Diagnostic>) {
+        context.scope = Arc::clone(&self.scope);
+        context.segment = Arc::clone(&self.segment);
+        context.block_position = block_position;
         let mut position = block_position;
         for chunk in &mut self.chunks {
-            chunk.resolve_parameters(position, lookup_table)?;
+            context.used_undefined_import = false;
+            if let Err(diagnostic) = chunk.resolve_parameters(position, context) {
+                if !context.used_undefined_import {
+                    diagnostics.push(diagnostic);
+                }
+            }
             position = position.wrapping_add(chunk.len() as u16);
         }
-        Ok(())
+        self.relocations = ::std::mem::replace(&mut context.relocations, Vec::new());
     }
 }
 
 impl ResolveParameters for IRChunk {
-    fn resolve_parameters(
-        &mut self,
-        chunk_position: u16,
-        lookup_table: &HashMap<Arc<String>, u16>,
-    ) -> error::Result<()> {
+    fn resolve_parameters(&mut self, chunk_position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic> {
         match *self {
-            IRChunk::Op(ref mut op) => op.resolve_parameters(chunk_position, lookup_table),
+            IRChunk::Op(ref mut op) => op.resolve_parameters(chunk_position, context),
+            IRChunk::Data(ref mut data) => data.resolve_parameters(chunk_position, context),
             IRChunk::Vector(tag, ref label, ref mut value) => {
-                if let Some(position) = lookup_table.get(label) {
-                    *value = *position;
-                    Ok(())
+                *value = context.lookup(tag, label)?;
+                context.relocate(&ast::Expr::Name(tag, Arc::clone(label)), chunk_position, 2)
+            }
+            IRChunk::Assert(ref assert) => assert.check(chunk_position, context),
+            IRChunk::RunAddress(ref mut run_address) => {
+                let value = context.evaluate(&run_address.expr, chunk_position)?;
+                run_address.address = address_value(run_address.tag, value)?;
+                Ok(())
+            }
+            IRChunk::Print(ref print) => {
+                let message = print.message(chunk_position, context)?;
+                context
+                    .diagnostics
+                    .push(Diagnostic::new(Severity::Note, DiagnosticCode::Message, message).with_span(print.tag));
+                Ok(())
+            }
+            IRChunk::PointerCheck(tag) => {
+                if chunk_position & 0xFF == 0xFF {
+                    Err(error_at(
+                        DiagnosticCode::JmpIndirectPageWrap,
+                        tag,
+                        format!("pointer at ${:04X} straddles a page boundary", chunk_position),
+                    ).with_note("`JMP (ptr)` on the NMOS 6502 would read its high byte from the start of the same page"))
                 } else {
-                    Err(AssemblerError(tag, format!("unknown label: \"{}\"", label)).into())
+                    Ok(())
                 }
             }
             _ => Ok(()),
@@ -118,50 +476,140 @@ impl ResolveParameters for IRChunk {
     }
 }
 
+impl IRAssert {
+    fn check(&self, position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic> {
+        if context.evaluate(&self.expr, position)? != 0 {
+            return Ok(());
+        }
+
+        let message = match self.message {
+            Some(ref message) => format!("assertion failed: {}", message),
+            None => "assertion failed".into(),
+        };
+        let mut diagnostic = error_at(DiagnosticCode::AssertionFailed, self.tag, message);
+        if let ast::Expr::Binary(_, operator, ref left, ref right) = self.expr {
+            if operator.is_comparison() {
+                diagnostic = diagnostic.with_note(format!(
+                    "left side is ${:X}, right side is ${:X}",
+                    context.evaluate(left, position)?,
+                    context.evaluate(right, position)?
+                ));
+            }
+        }
+        Err(diagnostic)
+    }
+}
+
+impl IRPrint {
+    fn message(&self, position: u16, context: &mut ResolveContext) -> Result<String, Diagnostic> {
+        let mut message = String::new();
+        for item in &self.items {
+            match *item {
+                ast::PrintItem::Str(ref text) => message.push_str(text),
+                ast::PrintItem::Expr(ref expr) => {
+                    let value = context.evaluate(expr, position)?;
+                    if value >= 0 {
+                        message.push_str(&format!("{} (${:X})", value, value));
+                    } else {
+                        message.push_str(&value.to_string());
+                    }
+                }
+            }
+        }
+        Ok(message)
+    }
+}
+
 impl ResolveParameters for IROp {
-    fn resolve_parameters(&mut self, op_position: u16, lookup_table: &HashMap<Arc<String>, u16>) -> error::Result<()> {
+    fn resolve_parameters(&mut self, op_position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic> {
         self.position = op_position;
-        self.param.resolve_parameters(op_position, lookup_table)?;
-        assert!(self.param.len() == Some(self.code.len - 1));
+        self.param.resolve_parameters(op_position, context)?;
+        let expected_len = self.code.len - 1;
+        match self.param.len() {
+            Some(len) if len == expected_len => {}
+            len => {
+                return Err(error_at(
+                    DiagnosticCode::OperandSize,
+                    self.tag,
+                    format!(
+                        "operand is {} byte(s), but this addressing mode takes {}",
+                        len.unwrap_or(0),
+                        expected_len
+                    ),
+                ))
+            }
+        }
+
+        if let IRParam::Resolved(OpAddressMode::Indirect, OpParam::Word(pointer)) = self.param {
+            if pointer & 0xFF == 0xFF {
+                let msg = format!(
+                    "JMP (${:04X}) reads its high byte from ${:04X} rather than ${:04X} on the NMOS 6502",
+                    pointer,
+                    pointer & 0xFF00,
+                    pointer.wrapping_add(1)
+                );
+                if context.cpu.has_indirect_jmp_bug() {
+                    return Err(error_at(DiagnosticCode::JmpIndirectPageWrap, self.tag, msg)
+                        .with_note("place the pointer with `.indirect` so that it can't start at $xxFF"));
+                } else {
+                    context.warn(Warning::JmpIndirectPageWrap, self.tag, msg);
+                }
+            }
+        }
+
+        if self.param.mode() == OpAddressMode::PCOffset {
+            if let IRParam::Resolved(_, OpParam::Byte(offset)) = self.param {
+                let pc = op_position.wrapping_add(2);
+                let target = pc.wrapping_add(offset as i8 as u16);
+                if pc & 0xFF00 != target & 0xFF00 {
+                    context.warn(
+                        Warning::BranchPageCross,
+                        self.tag,
+                        format!(
+                            "branch from ${:04X} to ${:04X} crosses a page boundary and takes an extra cycle",
+                            op_position, target
+                        ),
+                    );
+                }
+            }
+        }
         Ok(())
     }
 }
 
 impl ResolveParameters for IRParam {
-    fn resolve_parameters(&mut self, op_position: u16, lookup_table: &HashMap<Arc<String>, u16>) -> error::Result<()> {
-        let lookup = &|tag: SrcTag, name: &Arc<String>| {
-            if let Some(position) = lookup_table.get(name) {
-                Ok(*position)
-            } else {
-                Err(AssemblerError(tag, format!("unknown label: \"{}\"", name)))
-            }
-        };
-
+    fn resolve_parameters(&mut self, op_position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic> {
         let replacement = match *self {
             IRParam::Resolved(mode, param) => IRParam::Resolved(mode, param),
-            IRParam::Unresolved(mode, tag, ref name) => {
-                let position = lookup(tag, name)?;
-                // Absolute addresses must be converted to offsets for branch instructions
-                if mode == OpAddressMode::PCOffset {
-                    let pc = op_position.wrapping_add(2);
-                    let pc_offset = ((position as isize) - (pc as isize)) as i16;
-                    if pc_offset > 127 || pc_offset < -128 {
-                        // TODO: Refactor so that this code modification is possible
-                        let msg = "modifying code to fix branch offsets outside \
-                                   of range -128 to +127 is not currently supported";
-                        return Err(AssemblerError(tag, msg.into()).into());
-                    } else {
-                        IRParam::Resolved(mode, OpParam::Byte(pc_offset as u8))
-                    }
+            // Absolute addresses must be converted to offsets for branch instructions
+            IRParam::Unresolved(OpAddressMode::PCOffset, ref expr) => {
+                let target = context.evaluate(expr, op_position)?;
+                // A branch's offset can't be relocated to wherever an undefined import ends up
+                if let Ok(Some(RelocationBase::Import(_))) = context.relocation_base(expr) {
+                    return Err(not_relocatable(expr.tag()));
+                }
+                let pc = op_position.wrapping_add(2);
+                let pc_offset = target - pc as i64;
+                if pc_offset > 127 || pc_offset < -128 {
+                    // TODO: Refactor so that this code modification is possible
+                    let msg = "modifying code to fix branch offsets outside \
+                               of range -128 to +127 is not currently supported";
+                    return Err(Diagnostic::error(DiagnosticCode::BranchOutOfRange, msg)
+                        .with_span(expr.tag())
+                        .with_note(format!("branch target is {} bytes away", pc_offset)));
                 } else {
-                    IRParam::Resolved(mode, OpParam::Word(lookup(tag, name)?))
+                    IRParam::Resolved(OpAddressMode::PCOffset, OpParam::Byte(pc_offset as u8))
                 }
             }
-            IRParam::UnresolvedLowByte(mode, tag, ref name) => {
-                IRParam::Resolved(mode, OpParam::Byte(lookup(tag, name)? as u8))
+            IRParam::Unresolved(mode, ref expr) => {
+                let value = context.evaluate(expr, op_position)?;
+                context.relocate(expr, op_position.wrapping_add(1), 2)?;
+                IRParam::Resolved(mode, OpParam::Word(word_value(expr.tag(), value)?))
             }
-            IRParam::UnresolvedHighByte(mode, tag, ref name) => {
-                IRParam::Resolved(mode, OpParam::Byte((lookup(tag, name)? >> 8) as u8))
+            IRParam::UnresolvedByte(mode, ref expr) => {
+                let value = context.evaluate(expr, op_position)?;
+                context.relocate(expr, op_position.wrapping_add(1), 1)?;
+                IRParam::Resolved(mode, OpParam::Byte(byte_value(expr.tag(), value)?))
             }
         };
 
@@ -170,225 +618,1511 @@ impl ResolveParameters for IRParam {
     }
 }
 
+impl ResolveParameters for IRData {
+    fn resolve_parameters(&mut self, position: u16, context: &mut ResolveContext) -> Result<(), Diagnostic> {
+        self.bytes.clear();
+        for (index, expr) in self.exprs.iter().enumerate() {
+            let value = context.evaluate(expr, position)?;
+            context.relocate(expr, position.wrapping_add((index * self.width) as u16), self.width)?;
+            if self.width == 1 {
+                self.bytes.push(byte_value(expr.tag(), value)?);
+            } else {
+                let word = word_value(expr.tag(), value)?;
+                self.bytes.push(word as u8);
+                self.bytes.push((word >> 8) as u8);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Converts a value to a byte, allowing negative values down to -128
+fn byte_value(tag: SrcTag, value: i64) -> Result<u8, Diagnostic> {
+    if value >= -0x80 && value <= 0xFF {
+        Ok(value as u8)
+    } else {
+        Err(error_at(
+            DiagnosticCode::NumberOutOfRange,
+            tag,
+            format!("value {} doesn't fit in a byte", value),
+        ))
+    }
+}
+
+/// Converts a value to a word, allowing negative values down to -32768
+fn word_value(tag: SrcTag, value: i64) -> Result<u16, Diagnostic> {
+    if value >= -0x8000 && value <= 0xFFFF {
+        Ok(value as u16)
+    } else {
+        Err(error_at(
+            DiagnosticCode::NumberOutOfRange,
+            tag,
+            format!("value {} doesn't fit in a word", value),
+        ))
+    }
+}
+
+/// Converts a value to an address, which can't be negative
+fn address_value(tag: SrcTag, value: i64) -> Result<u16, Diagnostic> {
+    if value >= 0 && value <= 0xFFFF {
+        Ok(value as u16)
+    } else {
+        Err(error_at(
+            DiagnosticCode::NumberOutOfRange,
+            tag,
+            format!("value {} isn't an address", value),
+        ))
+    }
+}
+
+fn error_at<M: Into<String>>(code: DiagnosticCode, tag: SrcTag, message: M) -> Diagnostic {
+    Diagnostic::error(code, message).with_span(tag)
+}
+
+fn not_relocatable(tag: SrcTag) -> Diagnostic {
+    error_at(DiagnosticCode::NotRelocatable, tag, "value can't be relocated")
+        .with_note("relocatable output can only adjust an address plus or minus a number, or its low or high byte")
+}
+
+fn unknown_label(tag: SrcTag, name: &Arc<String>) -> Diagnostic {
+    error_at(DiagnosticCode::UnknownLabel, tag, format!("unknown label: \"{}\"", name))
+}
+
+/// Records where a label or constant is defined, failing if the name is already taken
+fn define_symbol(
+    definitions: &mut HashMap<Arc<String>, SrcTag>,
+    tag: SrcTag,
+    name: &Arc<String>,
+) -> Result<(), Diagnostic> {
+    if let Some(first) = definitions.get(name) {
+        return Err(error_at(
+            DiagnosticCode::DuplicateSymbol,
+            tag,
+            format!("\"{}\" is already defined", unlinked_name(name)),
+        ).with_label(*first, "first defined here"));
+    }
+    definitions.insert(Arc::clone(name), tag);
+    Ok(())
+}
+
+fn word_argument(tag: SrcTag, number: ast::Number, what: &str) -> Result<u16, Diagnostic> {
+    match number {
+        ast::Number::Byte(value) => Ok(value as u16),
+        ast::Number::Word(value) => Ok(value),
+        ast::Number::Invalid(_) => Err(error_at(
+            DiagnosticCode::NumberOutOfRange,
+            tag,
+            format!("{} must be a 16-bit number", what),
+        )),
+    }
+}
+
+/// Returns the given fill value, or the target's fill byte if there isn't one
+fn fill_argument(tag: SrcTag, fill: Option<ast::Number>, target: &Target) -> Result<u8, Diagnostic> {
+    match fill {
+        Some(ast::Number::Byte(value)) => Ok(value),
+        None => Ok(target.fill_byte),
+        _ => Err(error_at(
+            DiagnosticCode::NumberOutOfRange,
+            tag,
+            "fill value must be a byte",
+        )),
+    }
+}
+
 pub struct IRGenerator {}
 
 impl IRGenerator {
-    pub fn generate(units: &[ast::Statement]) -> error::Result<IR> {
-        let mut ir = IRGenerator::generate_ir(units)?;
-        IRGenerator::resolve(&mut ir)?;
-        Ok(ir)
+    /// Generates and resolves the IR. Warnings are filtered through the given configuration and
+    /// returned alongside the IR, or with the errors if assembly failed.
+    pub fn generate(
+        units: &[ast::Statement],
+        target: &Target,
+        warnings: &WarningConfig,
+    ) -> Result<(IR, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let (ir, mut diagnostics) = IRGenerator::generate_unresolved(units, target, warnings)?;
+        match IRGenerator::link(ir, target, warnings) {
+            Ok((ir, link_diagnostics)) => {
+                diagnostics.extend(link_diagnostics.into_iter());
+                Ok((ir, diagnostics))
+            }
+            Err(link_diagnostics) => {
+                diagnostics.extend(link_diagnostics.into_iter());
+                Err(diagnostics)
+            }
+        }
     }
 
-    fn resolve(ir: &mut IR) -> error::Result<()> {
-        for block in &mut ir.blocks {
-            block.resolve_length()?;
+    /// Generates the IR without assigning addresses, leaving references to labels and to constants that can't be
+    /// folded yet as unresolved params. This is what object files hold.
+    pub fn generate_unresolved(
+        units: &[ast::Statement],
+        target: &Target,
+        warnings: &WarningConfig,
+    ) -> Result<(IR, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        let ir = IRGenerator::generate_ir(units, target, &mut diagnostics);
+        diagnostics = warnings.apply(diagnostics);
+        if diagnostic::has_errors(&diagnostics) {
+            Err(diagnostics)
+        } else {
+            Ok((ir, diagnostics))
+        }
+    }
+
+    /// Places the blocks of unresolved IR, which may come from several object files, and resolves every
+    /// reference between them
+    pub fn link(
+        mut ir: IR,
+        target: &Target,
+        warnings: &WarningConfig,
+    ) -> Result<(IR, Vec<Diagnostic>), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        IRGenerator::resolve(&mut ir, target, &mut diagnostics);
+        let diagnostics = warnings.apply(diagnostics);
+        if diagnostic::has_errors(&diagnostics) {
+            Err(diagnostics)
+        } else {
+            Ok((ir, diagnostics))
+        }
+    }
+
+    fn resolve(ir: &mut IR, target: &Target, diagnostics: &mut Vec<Diagnostic>) {
+        let placed = match target.layout {
+            Some(ref layout) => IRGenerator::order_segments(ir, layout, diagnostics),
+            None => IRGenerator::check_default_segment(ir, diagnostics),
+        };
+        if !placed {
+            return;
         }
 
         let mut lookup_table: HashMap<Arc<String>, u16> = HashMap::new();
+        let mut definitions: HashMap<Arc<String>, SrcTag> = HashMap::new();
         let mut position = 0u16;
+        let mut load_position = 0u16;
+        let mut area = None;
+        // Where each area's segments end, which can be past the end of the area, and the first block past the end
+        let mut area_ends: Vec<u32> = Vec::new();
+        let mut area_overflows: Vec<Option<SrcTag>> = Vec::new();
+        if let Some(ref layout) = target.layout {
+            area_ends = layout.areas.iter().map(|area| area.start as u32).collect();
+            area_overflows = vec![None; layout.areas.len()];
+        }
         for block in &mut ir.blocks {
-            if let Some(pos) = block.position {
-                position = pos;
-            } else {
-                block.position = Some(position);
+            // Segments in the same area follow each other, even when segments in other areas come between them
+            if let Some(ref layout) = target.layout {
+                let block_area = layout.segment_area(&block.segment);
+                if block_area != area {
+                    area = block_area;
+                    if let Some(index) = area {
+                        position = area_ends[index] as u16;
+                        load_position = position;
+                    }
+                }
+            }
+            match (block.placement, block.position) {
+                (IRPlacement::Org, Some(pos)) => {
+                    position = pos;
+                    load_position = pos;
+                }
+                (IRPlacement::Relocate, Some(pos)) => position = pos,
+                (IRPlacement::EndRelocate, _) => position = load_position,
+                _ => {}
+            }
+            block.position = Some(position);
+            block.load_position = Some(load_position);
+
+            if let Err(diagnostic) = block.resolve_length(position as usize) {
+                diagnostics.push(diagnostic);
+                return;
             }
 
             if let Some(ref label) = block.label {
+                if let Err(diagnostic) = define_symbol(&mut definitions, block.tag, label) {
+                    diagnostics.push(diagnostic);
+                }
                 lookup_table.insert(Arc::clone(label), position);
             }
+            if let (Some(index), Some(ref layout)) = (area, target.layout.as_ref()) {
+                area_ends[index] += block.length as u32;
+                let area_limit = layout.areas[index].start as u32 + layout.areas[index].size;
+                if area_ends[index] > area_limit && area_overflows[index].is_none() {
+                    area_overflows[index] = Some(block.tag);
+                }
+                // The overflowing area is reported instead, with how much space its segments need
+                if area_overflows[index].is_some() {
+                    position = position.wrapping_add(block.length);
+                    load_position = load_position.wrapping_add(block.length);
+                    continue;
+                }
+            }
+            for &(address, kind) in &[(position, "runs at"), (load_position, "is loaded at")] {
+                if address as usize + block.length as usize > 0x10000 {
+                    diagnostics.push(error_at(
+                        DiagnosticCode::AddressOverflow,
+                        block.tag,
+                        format!(
+                            "block that {} ${:04X} extends {} byte(s) past $FFFF",
+                            kind,
+                            address,
+                            address as usize + block.length as usize - 0x10000
+                        ),
+                    ));
+                    return;
+                }
+            }
             position = position.wrapping_add(block.length);
+            load_position = load_position.wrapping_add(block.length);
+        }
+        if let Some(ref layout) = target.layout {
+            IRGenerator::report_area_overflows(ir, layout, &area_ends, &area_overflows, diagnostics);
+        }
+
+        let mut constants: PlacedConstants = HashMap::new();
+        for block in &ir.blocks {
+            let mut position = block.position.unwrap();
+            for chunk in &block.chunks {
+                if let IRChunk::Constant(ref constant) = *chunk {
+                    if let Err(diagnostic) = define_symbol(&mut definitions, constant.tag, &constant.name) {
+                        diagnostics.push(diagnostic);
+                    }
+                    constants.insert(
+                        Arc::clone(&constant.name),
+                        (
+                            constant.clone(),
+                            position,
+                            Arc::clone(&block.scope),
+                            Arc::clone(&block.segment),
+                        ),
+                    );
+                }
+                position = position.wrapping_add(chunk.len() as u16);
+            }
         }
 
+        let undefined_imports =
+            IRGenerator::link_names(ir, &mut constants, target.format.is_relocatable(), diagnostics);
+
+        let mut scope_sizes: HashMap<Arc<String>, i64> = HashMap::new();
+        for block in &ir.blocks {
+            let mut scope = &block.scope[..];
+            while !scope.is_empty() {
+                *scope_sizes.entry(Arc::new(scope.into())).or_insert(0) += block.length as i64;
+                scope = match scope.rfind("::") {
+                    Some(index) => &scope[..index],
+                    None => "",
+                };
+            }
+        }
+
+        let label_segments: HashMap<Arc<String>, Arc<String>> = ir.blocks
+            .iter()
+            .filter_map(|block| {
+                block
+                    .label
+                    .as_ref()
+                    .map(|label| (Arc::clone(label), Arc::clone(&block.segment)))
+            })
+            .collect();
+        let mut context = ResolveContext::new(target, &lookup_table, &constants, &scope_sizes, &label_segments);
+        context.undefined_imports = undefined_imports;
         for block in &mut ir.blocks {
             position = block.position.unwrap();
-            block.resolve_parameters(position, &lookup_table)?;
+            block.resolve_chunks(position, &mut context, diagnostics);
+        }
+        if target.format.is_relocatable() {
+            context.scope = Arc::new(String::new());
+            for export in &mut ir.exports {
+                match context.symbol_base(&Arc::new(format!("::{}", export.local))) {
+                    Ok(Some(RelocationBase::Segment(segment))) => export.segment = Some(segment),
+                    Ok(None) => export.segment = None,
+                    Ok(Some(RelocationBase::Import(_))) => diagnostics.push(not_relocatable(export.tag)),
+                    Err(diagnostic) => diagnostics.push(diagnostic),
+                }
+            }
         }
 
-        Ok(())
+        for block in &ir.blocks {
+            if let Some(ref label) = block.label {
+                if !context.used_labels.contains(label) {
+                    context.warn(
+                        Warning::UnusedLabel,
+                        block.tag,
+                        format!("label \"{}\" is never used", unlinked_name(label)),
+                    );
+                }
+            }
+        }
+
+        let mut symbols: BTreeMap<Arc<String>, i64> = lookup_table
+            .iter()
+            .map(|(name, position)| (Arc::clone(name), *position as i64))
+            .collect();
+        // Imports are listed by the unit that exports them
+        let listed = constants.keys().filter(|name| {
+            !unlinked_name(name).starts_with('*') && !ir.imports.iter().any(|import| import.local == **name)
+        });
+        for name in listed {
+            if let Ok(value) = context.constant_value(name) {
+                symbols.insert(Arc::clone(name), value);
+            }
+        }
+        ir.symbols = symbols;
+        diagnostics.extend(context.diagnostics.into_iter());
+    }
+
+    /// Checks that every import is exported by exactly one unit, and returns the names of the imports that aren't
+    /// exported by local name. Relocatable output can leave them undefined. Linked objects refer to each other's
+    /// names through constants that tie each export and import to the name in its module.
+    fn link_names(
+        ir: &IR,
+        constants: &mut PlacedConstants,
+        relocatable: bool,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> HashMap<Arc<String>, Arc<String>> {
+        let mut undefined = HashMap::new();
+        let mut exported: HashMap<&Arc<String>, SrcTag> = HashMap::new();
+        let mut links = Vec::new();
+        for export in &ir.exports {
+            if let Some(&first) = exported.get(&export.name) {
+                diagnostics.push(error_at(
+                    DiagnosticCode::DuplicateExport,
+                    export.tag,
+                    format!("\"{}\" is exported by more than one unit", export.name),
+                ).with_label(first, "also exported here"));
+                continue;
+            }
+            exported.insert(&export.name, export.tag);
+            links.push((export.tag, &export.name, &export.local));
+        }
+        for import in &ir.imports {
+            if exported.contains_key(&import.name) {
+                links.push((import.tag, &import.local, &import.name));
+            } else {
+                if !relocatable {
+                    diagnostics.push(error_at(
+                        DiagnosticCode::UndefinedImport,
+                        import.tag,
+                        format!("\"{}\" is imported, but no unit exports it", import.name),
+                    ));
+                }
+                undefined.insert(Arc::clone(&import.local), Arc::clone(&import.name));
+            }
+        }
+
+        for (tag, name, target) in links {
+            if name != target {
+                let expr = ast::Expr::Name(tag, Arc::new(format!("::{}", target)));
+                let constant = IRConstant::new(tag, Arc::clone(name), expr);
+                let global = Arc::new(String::new());
+                constants.insert(Arc::clone(name), (constant, 0, Arc::clone(&global), global));
+            }
+        }
+        undefined
+    }
+
+    /// Puts the blocks in the order that their segments are placed in, keeping the source order within each segment
+    fn order_segments(ir: &mut IR, layout: &MemoryLayout, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let mut reported = HashSet::new();
+        let mut placed = true;
+        for block in &ir.blocks {
+            // Object files can be assembled without a layout, so `.org` is checked again here
+            if block.placement == IRPlacement::Org {
+                diagnostics.push(error_at(
+                    DiagnosticCode::OrgWithLayout,
+                    block.tag,
+                    "`.org` can't be used with a memory layout",
+                ).with_note("use `.segment` to choose where code is placed"));
+                placed = false;
+            }
+            if block.needs_placement() && layout.segment_index(&block.segment).is_none()
+                && reported.insert(&block.segment)
+            {
+                diagnostics.push(error_at(
+                    DiagnosticCode::UnknownSegment,
+                    block.tag,
+                    format!("segment \"{}\" isn't in the memory layout", block.segment),
+                ).with_note("code before the first `.segment` goes in the \"CODE\" segment"));
+                placed = false;
+            }
+        }
+        if !placed {
+            return false;
+        }
+
+        ir.blocks
+            .sort_by_key(|block| layout.segment_index(&block.segment).unwrap_or(layout.segments.len()));
+        true
     }
 
-    fn generate_ir(units: &[ast::Statement]) -> error::Result<IR> {
+    /// Without a layout, everything has to stay in the default segment
+    fn check_default_segment(ir: &IR, diagnostics: &mut Vec<Diagnostic>) -> bool {
+        let segmented = ir.blocks
+            .iter()
+            .find(|block| block.needs_placement() && *block.segment != DEFAULT_SEGMENT);
+        match segmented {
+            Some(block) => {
+                diagnostics.push(error_at(
+                    DiagnosticCode::UnknownSegment,
+                    block.tag,
+                    "segments can't be used without a memory layout",
+                ));
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Reports areas whose segments don't fit, along with how much space each segment takes
+    fn report_area_overflows(
+        ir: &IR,
+        layout: &MemoryLayout,
+        area_ends: &[u32],
+        area_overflows: &[Option<SrcTag>],
+        diagnostics: &mut Vec<Diagnostic>,
+    ) {
+        for (area, (&end, &overflow)) in layout.areas.iter().zip(area_ends.iter().zip(area_overflows)) {
+            let tag = match overflow {
+                Some(tag) => tag,
+                None => continue,
+            };
+            let used = end - area.start as u32;
+            let mut diagnostic = error_at(
+                DiagnosticCode::AreaOverflow,
+                tag,
+                format!(
+                    "area \"{}\" is {} byte(s), but its segments take {} byte(s), which is {} too many",
+                    area.name,
+                    area.size,
+                    used,
+                    used - area.size
+                ),
+            );
+            for segment in layout.segments.iter().filter(|segment| segment.area == area.name) {
+                let size: u32 = ir.blocks
+                    .iter()
+                    .filter(|block| *block.segment == segment.name)
+                    .map(|block| block.length as u32)
+                    .sum();
+                diagnostic = diagnostic.with_note(format!("segment \"{}\" takes {} byte(s)", segment.name, size));
+            }
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    fn generate_ir(units: &[ast::Statement], target: &Target, diagnostics: &mut Vec<Diagnostic>) -> IR {
         let mut builder = IRBuilder::new();
         for statement in units {
-            use ast::Statement::*;
-            match *statement {
-                Error => unreachable!(),
-                Comment => {}
-                Label(_tag, ref label) => {
-                    builder.new_block(None, Some(Arc::clone(label)));
-                }
-                Instruction(tag, ref name, ref operand) => {
-                    if let Some(op_class) = OpClass::from_name(&*name) {
-                        let mut param = IRGenerator::resolve_operand(operand)?;
-                        if op_class.is_branch() && !op_class.is_jump() {
-                            param = param.with_mode(OpAddressMode::PCOffset);
-                        }
-                        if let Some(op_code) = OpCode::find_by_class_and_mode(op_class, param.mode()) {
-                            builder
-                                .current_block()
-                                .add_op(IROp::new(tag, op_code, param, 0));
-                        } else {
-                            return Err(AssemblerError(tag, format!("op {} requires a parameter", name)).into());
+            if let Err(diagnostic) = IRGenerator::generate_statement(&mut builder, statement, target, diagnostics) {
+                diagnostics.push(diagnostic);
+            }
+        }
+        if let Some(tag) = builder.relocation {
+            diagnostics.push(error_at(
+                DiagnosticCode::UnbalancedRelocation,
+                tag,
+                "`.rorg` is never ended with `.endrorg`",
+            ));
+        }
+        for scope in &builder.scopes {
+            diagnostics.push(error_at(
+                DiagnosticCode::UnbalancedScope,
+                scope.tag,
+                format!("`{}` is never ended with `{}`", scope.kind.directive(), scope.kind.end_directive()),
+            ));
+        }
+        builder.build(diagnostics)
+    }
+
+    fn generate_statement(
+        builder: &mut IRBuilder,
+        statement: &ast::Statement,
+        target: &Target,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> Result<(), Diagnostic> {
+        use ast::Statement::*;
+        match *statement {
+            Error => unreachable!(),
+            Comment => {}
+            Label(tag, ref label) => {
+                let label = builder.define_fixed(tag, label)?;
+                builder.new_block(tag, None, Some(label));
+            }
+            Constant(tag, ref name, ref expr) => {
+                let expr = builder.substitute(expr);
+                builder.define_constant(tag, name, expr)?;
+            }
+            Instruction(tag, ref name, ref written_operand) => {
+                if let Some(op_class) = OpClass::from_name(&*name) {
+                    let operand = builder.substitute_operand(written_operand);
+                    let param = match operand {
+                        ast::Operand::Address(expr) if op_class.is_branch() && !op_class.is_jump() => {
+                            IRParam::Unresolved(OpAddressMode::PCOffset, expr)
                         }
+                        _ => IRGenerator::resolve_operand(&operand, builder)?,
+                    };
+                    let param = IRGenerator::apply_zero_page_hint(param, op_class, builder);
+                    if IRGenerator::is_implicit_zero_page(written_operand, &param) {
+                        diagnostics.push(
+                            Diagnostic::warning(
+                                Warning::ImplicitZeroPage,
+                                format!("{} uses zero page addressing because its address fits in a byte", name),
+                            ).with_span(tag),
+                        );
+                    }
+                    if let Some(op_code) = OpCode::find_by_class_and_mode(op_class, param.mode()) {
+                        builder
+                            .current_block(tag)
+                            .add_op(IROp::new(tag, op_code, param, 0));
                     } else {
-                        return Err(AssemblerError(tag, format!("unknown opcode: {}", name)).into());
+                        return Err(error_at(
+                            DiagnosticCode::InvalidAddressMode,
+                            tag,
+                            format!("op {} requires a parameter", name),
+                        ));
                     }
+                } else {
+                    return Err(error_at(
+                        DiagnosticCode::UnknownOpcode,
+                        tag,
+                        format!("unknown opcode: {}", name),
+                    ));
                 }
-                MetaInstruction(ref meta_inst) => match *meta_inst {
-                    ast::MetaInstruction::Org(tag, number) => {
-                        match number {
-                            ast::Number::Byte(location) => builder.new_block(Some(location as u16), None),
-                            ast::Number::Word(location) => builder.new_block(Some(location), None),
-                            _ => return Err(AssemblerError(tag, "org must be a 16-bit address".into()).into()),
-                        }
+            }
+            MetaInstruction(ref meta_inst) => match *meta_inst {
+                ast::MetaInstruction::Org(tag, number) => {
+                    if target.layout.is_some() {
+                        return Err(error_at(
+                            DiagnosticCode::OrgWithLayout,
+                            tag,
+                            "`.org` can't be used with a memory layout",
+                        ).with_note("use `.segment` to choose where code is placed"));
+                    }
+                    if let Some(rorg_tag) = builder.relocation {
+                        return Err(error_at(
+                            DiagnosticCode::UnbalancedRelocation,
+                            tag,
+                            "`.org` can't be used inside a `.rorg` region",
+                        ).with_label(rorg_tag, "region starts here"));
                     }
-                    ast::MetaInstruction::Pad(tag, number) => {
-                        match number {
-                            ast::Number::Byte(location) => builder.new_block(Some(location as u16), None),
-                            ast::Number::Word(location) => builder.new_block(Some(location), None),
-                            _ => return Err(AssemblerError(tag, "pad requires a 16-bit address".into()).into()),
+                    match number {
+                        ast::Number::Byte(location) => builder.new_block(tag, Some(location as u16), None),
+                        ast::Number::Word(location) => builder.new_block(tag, Some(location), None),
+                        _ => {
+                            return Err(error_at(
+                                DiagnosticCode::NumberOutOfRange,
+                                tag,
+                                "org must be a 16-bit address",
+                            ))
                         }
                     }
-                    ast::MetaInstruction::Byte(tag, ref numbers) => {
-                        let mut bytes = Vec::new();
-                        for num in numbers {
-                            if let ast::Number::Byte(byte) = *num {
-                                bytes.push(byte);
-                            } else {
-                                return Err(AssemblerError(tag, "byte constants must be in range".into()).into());
-                            }
+                }
+                ast::MetaInstruction::Rorg(tag, number) => {
+                    if let Some(rorg_tag) = builder.relocation {
+                        return Err(error_at(
+                            DiagnosticCode::UnbalancedRelocation,
+                            tag,
+                            "`.rorg` regions can't be nested",
+                        ).with_label(rorg_tag, "enclosing region starts here"));
+                    }
+                    match number {
+                        ast::Number::Byte(location) => builder.start_relocation(tag, location as u16),
+                        ast::Number::Word(location) => builder.start_relocation(tag, location),
+                        _ => {
+                            return Err(error_at(
+                                DiagnosticCode::NumberOutOfRange,
+                                tag,
+                                "rorg must be a 16-bit address",
+                            ))
                         }
-                        builder.current_block().add_bytes(bytes);
                     }
-                    ast::MetaInstruction::Word(tag, ref numbers) => {
-                        let mut bytes = Vec::new();
-                        for num in numbers {
-                            if let ast::Number::Byte(byte) = *num {
-                                bytes.push(byte);
-                                bytes.push(0);
-                            } else if let ast::Number::Word(word) = *num {
-                                bytes.push(word as u8);
-                                bytes.push((word >> 8) as u8);
-                            } else {
-                                return Err(AssemblerError(tag, "word constants must be in range".into()).into());
-                            }
+                }
+                ast::MetaInstruction::EndRorg(tag) => {
+                    if builder.relocation.is_none() {
+                        return Err(error_at(
+                            DiagnosticCode::UnbalancedRelocation,
+                            tag,
+                            "`.endrorg` without a matching `.rorg`",
+                        ));
+                    }
+                    builder.end_relocation(tag);
+                }
+                ast::MetaInstruction::Pad(tag, number, fill) => {
+                    let location = word_argument(tag, number, "pad address")?;
+                    let value = fill_argument(tag, fill, target)?;
+                    builder
+                        .current_block(tag)
+                        .add_fill(tag, IRFillKind::PadTo(location), value);
+                }
+                ast::MetaInstruction::Align(tag, number, fill) => {
+                    let alignment = word_argument(tag, number, "alignment")?;
+                    if alignment == 0 {
+                        return Err(error_at(
+                            DiagnosticCode::NumberOutOfRange,
+                            tag,
+                            "alignment must be at least 1",
+                        ));
+                    }
+                    let value = fill_argument(tag, fill, target)?;
+                    builder
+                        .current_block(tag)
+                        .add_fill(tag, IRFillKind::Align(alignment), value);
+                }
+                ast::MetaInstruction::Res(tag, count, fill) => {
+                    let count = word_argument(tag, count, "reserved size")?;
+                    let value = fill_argument(tag, fill, target)?;
+                    builder
+                        .current_block(tag)
+                        .add_fill(tag, IRFillKind::Reserve(count), value);
+                }
+                ast::MetaInstruction::Fill(tag, count, value) => {
+                    let count = word_argument(tag, count, "fill count")?;
+                    let value = fill_argument(tag, Some(value), target)?;
+                    builder
+                        .current_block(tag)
+                        .add_fill(tag, IRFillKind::Repeat(count), value);
+                }
+                ast::MetaInstruction::Byte(tag, ref exprs) => {
+                    let exprs = exprs.iter().map(|expr| builder.substitute(expr)).collect();
+                    builder.current_block(tag).add_data(tag, 1, exprs);
+                }
+                ast::MetaInstruction::Word(tag, ref exprs) => {
+                    let exprs = exprs.iter().map(|expr| builder.substitute(expr)).collect();
+                    builder.current_block(tag).add_data(tag, 2, exprs);
+                }
+                ast::MetaInstruction::Vector(tag, ref label) => {
+                    builder.current_block(tag).add_vector(tag, label);
+                }
+                ast::MetaInstruction::Indirect(tag, ref label) => {
+                    let block = builder.current_block(tag);
+                    block.add_pointer_check(tag);
+                    block.add_vector(tag, label);
+                }
+                ast::MetaInstruction::Assert(tag, ref expr, ref message) => {
+                    let expr = builder.substitute(expr);
+                    builder
+                        .current_block(tag)
+                        .add_assert(IRAssert::new(tag, expr, message.clone()));
+                }
+                ast::MetaInstruction::Set(tag, ref name, ref expr) => {
+                    builder.set_variable(tag, name, expr)?;
+                }
+                ast::MetaInstruction::Print(tag, ref items) => {
+                    let items = items
+                        .iter()
+                        .map(|item| match *item {
+                            ast::PrintItem::Expr(ref expr) => ast::PrintItem::Expr(builder.substitute(expr)),
+                            ref item => item.clone(),
+                        })
+                        .collect();
+                    builder.current_block(tag).add_print(IRPrint::new(tag, items));
+                }
+                ast::MetaInstruction::Error(tag, ref message) => {
+                    return Err(error_at(DiagnosticCode::UserError, tag, message.as_str()));
+                }
+                ast::MetaInstruction::Warning(tag, ref message) => {
+                    diagnostics.push(Diagnostic::warning(Warning::User, message.as_str()).with_span(tag));
+                }
+                ast::MetaInstruction::Scope(tag, ref name) => {
+                    builder.open_scope(tag, ScopeKind::Scope, name)?;
+                }
+                ast::MetaInstruction::EndScope(tag) => {
+                    builder.close_scope(tag, ScopeKind::Scope)?;
+                }
+                ast::MetaInstruction::Proc(tag, ref name) => {
+                    let label = builder.define_fixed(tag, name)?;
+                    builder.open_scope(tag, ScopeKind::Proc, name)?;
+                    builder.new_block(tag, None, Some(label));
+                }
+                ast::MetaInstruction::EndProc(tag) => {
+                    builder.close_scope(tag, ScopeKind::Proc)?;
+                }
+                ast::MetaInstruction::Export(_, ref names) => builder.declare_links(names, Linkage::Export, false)?,
+                ast::MetaInstruction::Import(_, ref names, zero_page) => {
+                    builder.declare_links(names, Linkage::Import, zero_page)?
+                }
+                ast::MetaInstruction::Global(_, ref names, zero_page) => {
+                    builder.declare_links(names, Linkage::Global, zero_page)?
+                }
+                ast::MetaInstruction::Removable(tag) => builder.mark_removable(tag)?,
+                ast::MetaInstruction::BasicStub(tag, ref label) => {
+                    IRGenerator::generate_basic_stub(builder, tag, label);
+                }
+                ast::MetaInstruction::Run(tag, ref expr) => {
+                    let expr = builder.substitute(expr);
+                    builder
+                        .current_block(tag)
+                        .add_run_address(IRRunAddress::new(tag, IRRunKind::Run, expr));
+                }
+                ast::MetaInstruction::Init(tag, ref expr) => {
+                    let expr = builder.substitute(expr);
+                    builder
+                        .current_block(tag)
+                        .add_run_address(IRRunAddress::new(tag, IRRunKind::Init, expr));
+                }
+                ast::MetaInstruction::Ines(tag, ref setting) => builder.set_ines(tag, setting)?,
+                ast::MetaInstruction::Segment(tag, ref name) => {
+                    // Without a layout, segments are checked when linking
+                    if let Some(ref layout) = target.layout {
+                        if layout.segment_index(name).is_none() {
+                            return Err(error_at(
+                                DiagnosticCode::UnknownSegment,
+                                tag,
+                                format!("segment \"{}\" isn't in the memory layout", name),
+                            ));
                         }
-                        builder.current_block().add_bytes(bytes);
                     }
-                    ast::MetaInstruction::Vector(tag, ref label) => {
-                        builder.current_block().add_vector(tag, label);
+                    if let Some(rorg_tag) = builder.relocation {
+                        return Err(error_at(
+                            DiagnosticCode::UnbalancedRelocation,
+                            tag,
+                            "`.segment` can't be used inside a `.rorg` region",
+                        ).with_label(rorg_tag, "region starts here"));
                     }
-                    ast::MetaInstruction::Include(_, _) => {}
-                },
+                    builder.start_segment(tag, name);
+                }
+                ast::MetaInstruction::Struct(tag, ref name, ref members) => {
+                    let scope = builder.qualify_definition(tag, name)?;
+                    let outer = builder.enter_scope(scope);
+                    let result = IRGenerator::generate_struct(builder, tag, members);
+                    builder.enter_scope(outer);
+                    result?;
+                }
+                ast::MetaInstruction::Enum(tag, ref name, ref members) => {
+                    let scope = builder.qualify_definition(tag, name)?;
+                    let outer = builder.enter_scope(scope);
+                    let result = IRGenerator::generate_enum(builder, tag, members);
+                    builder.enter_scope(outer);
+                    result?;
+                }
+                ast::MetaInstruction::Include(_, _) | ast::MetaInstruction::IncBin(_, _) => {}
+            },
+        }
+        Ok(())
+    }
+
+    /// Defines a constant for the offset of each member, and `.sizeof` for the total size
+    fn generate_struct(builder: &mut IRBuilder, tag: SrcTag, members: &[ast::StructMember]) -> Result<(), Diagnostic> {
+        let mut offset = 0u32;
+        for member in members {
+            if let Some(ref name) = member.name {
+                builder.define_constant(member.tag, name, ast::Expr::from_value(member.tag, offset as i64))?;
+            }
+            offset += word_argument(member.tag, member.size, "member size")? as u32;
+            if offset > 0x10000 {
+                return Err(error_at(
+                    DiagnosticCode::NumberOutOfRange,
+                    member.tag,
+                    "struct is larger than 64KB",
+                ));
+            }
+        }
+        let size_name = Arc::new(eval::SIZE_MEMBER.to_string());
+        builder.define_constant(tag, &size_name, ast::Expr::from_value(tag, offset as i64))?;
+        Ok(())
+    }
+
+    /// Defines a constant for each member, counting up from zero or from the last member given a value
+    fn generate_enum(builder: &mut IRBuilder, tag: SrcTag, members: &[ast::EnumMember]) -> Result<(), Diagnostic> {
+        let mut next = ast::Expr::Number(tag, ast::Number::Byte(0));
+        for member in members {
+            let value = match member.value {
+                Some(ref expr) => builder.substitute(expr),
+                None => next,
+            };
+            let name = builder.define_constant(member.tag, &member.name, value)?;
+            next = ast::Expr::Binary(
+                member.tag,
+                ast::BinaryOperator::Add,
+                Box::new(ast::Expr::Name(member.tag, Arc::new(format!("::{}", name)))),
+                Box::new(ast::Expr::Number(member.tag, ast::Number::Byte(1))),
+            );
+            if let Some(value) = builder.constant_value(&next) {
+                next = ast::Expr::from_value(member.tag, value);
             }
         }
-        Ok(builder.build())
+        Ok(())
     }
 
-    fn resolve_operand(operand: &ast::Operand) -> error::Result<IRParam> {
+    /// Writes `10 SYS <address>` as a tokenized BASIC line, followed by the end of the program. The address gets a
+    /// field of five digits so that the size is known before the label is placed, and the digits that it doesn't
+    /// use are left as zeros after the end of the program.
+    fn generate_basic_stub(builder: &mut IRBuilder, tag: SrcTag, label: &Arc<String>) {
+        use ast::BinaryOperator::*;
+        const LINE_NUMBER: usize = 10;
+        const SYS_TOKEN: usize = 0x9E;
+        const DIGITS: usize = 5;
+
+        let number = |value: usize| ast::Expr::Number(tag, ast::Number::from_value(value));
+        let binary = |operator, left, right| ast::Expr::Binary(tag, operator, Box::new(left), Box::new(right));
+        let address = builder.substitute(&ast::Expr::Name(tag, Arc::clone(label)));
+        let length = (1..DIGITS as u32).fold(number(1), |length, power| {
+            let more = binary(GreaterEqual, address.clone(), number(10usize.pow(power)));
+            binary(Add, length, more)
+        });
+        // The next line's link is the end of the program, which follows the zero that ends this line
+        let link = binary(
+            Add,
+            ast::Expr::ProgramCounter(tag),
+            binary(Add, number(6), length.clone()),
+        );
+
+        let mut line = vec![
+            ast::Expr::Unary(tag, ast::UnaryOperator::LowByte, Box::new(link.clone())),
+            ast::Expr::Unary(tag, ast::UnaryOperator::HighByte, Box::new(link)),
+            number(LINE_NUMBER),
+            number(0),
+            number(SYS_TOKEN),
+        ];
+        for index in 0..DIGITS {
+            // Only the term for the address's actual length is nonzero
+            let character = (index + 1..DIGITS + 1).fold(number(0), |character, digits| {
+                let place = number(10usize.pow((digits - 1 - index) as u32));
+                let digit = binary(Modulo, binary(Divide, address.clone(), place), number(10));
+                let term = binary(
+                    Multiply,
+                    binary(Equal, length.clone(), number(digits)),
+                    binary(Add, number(b'0' as usize), digit),
+                );
+                binary(Add, character, term)
+            });
+            line.push(character);
+        }
+        // The zero that ends the line, and the zero link that ends the program
+        line.extend((0..3).map(|_| number(0)));
+
+        builder.current_block(tag).add_data(tag, 1, line);
+    }
+
+    fn is_implicit_zero_page(operand: &ast::Operand, param: &IRParam) -> bool {
+        let written_as_number = match *operand {
+            ast::Operand::Address(ast::Expr::Number(_, _))
+            | ast::Operand::AbsoluteX(ast::Expr::Number(_, _))
+            | ast::Operand::AbsoluteY(ast::Expr::Number(_, _)) => true,
+            _ => false,
+        };
+        let zero_page = match param.mode() {
+            OpAddressMode::ZeroPage | OpAddressMode::ZeroPageOffsetX | OpAddressMode::ZeroPageOffsetY => true,
+            _ => false,
+        };
+        written_as_number && zero_page
+    }
+
+    /// Uses zero page addressing for operands that refer to names imported with `.importzp` or `.globalzp`
+    fn apply_zero_page_hint(param: IRParam, op_class: OpClass, builder: &IRBuilder) -> IRParam {
+        match param {
+            IRParam::Unresolved(mode, expr) => {
+                let zero_page = zero_page_mode(mode);
+                if zero_page != mode && builder.is_zero_page_import(&expr)
+                    && OpCode::find_by_class_and_mode(op_class, zero_page).is_some()
+                {
+                    IRParam::UnresolvedByte(zero_page, expr)
+                } else {
+                    IRParam::Unresolved(mode, expr)
+                }
+            }
+            param => param,
+        }
+    }
+
+    fn resolve_operand(operand: &ast::Operand, builder: &IRBuilder) -> Result<IRParam, Diagnostic> {
         use ast::Operand::*;
         match *operand {
             None => Ok(IRParam::Resolved(OpAddressMode::Implied, OpParam::None)),
-            Immediate(modifier, ref term) => IRGenerator::resolve_param(term, modifier, OpAddressMode::Immediate),
-            Address(modifier, ref term) => IRGenerator::resolve_param(term, modifier, OpAddressMode::Absolute),
-            AbsoluteX(ref term) => IRGenerator::resolve_param(
-                term,
-                ast::OperandModifier::None,
-                OpAddressMode::AbsoluteOffsetX,
-            ),
-            AbsoluteY(ref term) => IRGenerator::resolve_param(
-                term,
-                ast::OperandModifier::None,
-                OpAddressMode::AbsoluteOffsetY,
-            ),
-            Indirect(ref term) => IRGenerator::resolve_param(term, ast::OperandModifier::None, OpAddressMode::Indirect),
-            IndirectX(ref term) => IRGenerator::resolve_param(
-                term,
-                ast::OperandModifier::None,
-                OpAddressMode::PreIndirectX,
-            ),
-            IndirectY(ref term) => IRGenerator::resolve_param(
-                term,
-                ast::OperandModifier::None,
-                OpAddressMode::PostIndirectY,
-            ),
+            Immediate(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::Immediate, builder),
+            Address(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::Absolute, builder),
+            AbsoluteX(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::AbsoluteOffsetX, builder),
+            AbsoluteY(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::AbsoluteOffsetY, builder),
+            Indirect(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::Indirect, builder),
+            IndirectX(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::PreIndirectX, builder),
+            IndirectY(ref expr) => IRGenerator::resolve_param(expr, OpAddressMode::PostIndirectY, builder),
+        }
+    }
+
+    /// Resolves operands that only depend on numbers and earlier constants now, so that zero page addressing
+    /// can be used for them. Anything else is left for `resolve`, and is assumed to be a word unless it's the
+    /// low or high byte of a value.
+    fn resolve_param(
+        expr: &ast::Expr,
+        mode: OpAddressMode,
+        builder: &IRBuilder,
+    ) -> Result<IRParam, Diagnostic> {
+        if let Some(value) = builder.constant_value(expr) {
+            return IRGenerator::value_to_param(expr.tag(), value, mode);
+        }
+
+        let byte_sized = match *expr {
+            ast::Expr::Unary(_, ast::UnaryOperator::LowByte, _)
+            | ast::Expr::Unary(_, ast::UnaryOperator::HighByte, _)
+            | ast::Expr::Unary(_, ast::UnaryOperator::BankByte, _) => true,
+            _ => mode == OpAddressMode::Immediate,
+        };
+        if byte_sized {
+            Ok(IRParam::UnresolvedByte(zero_page_mode(mode), expr.clone()))
+        } else {
+            Ok(IRParam::Unresolved(mode, expr.clone()))
         }
     }
 
-    fn resolve_param(term: &ast::Term, modifier: ast::OperandModifier, mode: OpAddressMode) -> error::Result<IRParam> {
-        match *term {
-            ast::Term::Number(tag, ref num) => IRGenerator::num_to_param(tag, num, modifier, mode),
-            ast::Term::Name(tag, ref name) => IRGenerator::name_to_param(tag, name, modifier, mode),
+    fn value_to_param(tag: SrcTag, value: i64, mode: OpAddressMode) -> Result<IRParam, Diagnostic> {
+        if mode == OpAddressMode::Immediate && value >= -0x80 && value < 0 {
+            Ok(IRParam::Resolved(mode, OpParam::Byte(value as u8)))
+        } else if value >= 0 && value <= 0xFF {
+            // If we're only a byte wide, then we can take advantage of faster address modes
+            Ok(IRParam::Resolved(zero_page_mode(mode), OpParam::Byte(value as u8)))
+        } else if value > 0xFF && value <= 0xFFFF {
+            Ok(IRParam::Resolved(mode, OpParam::Word(value as u16)))
+        } else {
+            Err(error_at(
+                DiagnosticCode::NumberOutOfRange,
+                tag,
+                "number not within 8-bit or 16-bit bounds",
+            ))
         }
     }
+}
 
-    fn name_to_param(
-        tag: SrcTag,
-        name: &Arc<String>,
-        modifier: ast::OperandModifier,
-        mode: OpAddressMode,
-    ) -> error::Result<IRParam> {
-        match modifier {
-            ast::OperandModifier::None => Ok(IRParam::Unresolved(mode, tag, Arc::clone(name))),
-            ast::OperandModifier::HighByte => Ok(IRParam::UnresolvedHighByte(mode, tag, Arc::clone(name))),
-            ast::OperandModifier::LowByte => Ok(IRParam::UnresolvedLowByte(mode, tag, Arc::clone(name))),
+fn replace_program_counter(expr: &ast::Expr, name: &Arc<String>) -> ast::Expr {
+    match *expr {
+        ast::Expr::ProgramCounter(tag) => ast::Expr::Name(tag, Arc::clone(name)),
+        ast::Expr::Unary(tag, operator, ref operand) => {
+            ast::Expr::Unary(tag, operator, Box::new(replace_program_counter(operand, name)))
+        }
+        ast::Expr::Binary(tag, operator, ref left, ref right) => ast::Expr::Binary(
+            tag,
+            operator,
+            Box::new(replace_program_counter(left, name)),
+            Box::new(replace_program_counter(right, name)),
+        ),
+        ast::Expr::Call(tag, function, ref arguments) => ast::Expr::Call(
+            tag,
+            function,
+            arguments
+                .iter()
+                .map(|argument| replace_program_counter(argument, name))
+                .collect(),
+        ),
+        ast::Expr::Number(_, _) | ast::Expr::Name(_, _) | ast::Expr::SizeOf(_, _) | ast::Expr::Defined(_, _) => {
+            expr.clone()
         }
     }
+}
 
-    fn num_to_param(
-        tag: SrcTag,
-        num: &ast::Number,
-        modifier: ast::OperandModifier,
-        mode: OpAddressMode,
-    ) -> error::Result<IRParam> {
-        match *num {
-            ast::Number::Byte(val) => {
-                // If we're only a byte wide, then we can take advantage of faster address modes
-                let corrected_mode = match mode {
-                    OpAddressMode::Absolute => OpAddressMode::ZeroPage,
-                    OpAddressMode::AbsoluteOffsetX => OpAddressMode::ZeroPageOffsetX,
-                    OpAddressMode::AbsoluteOffsetY => OpAddressMode::ZeroPageOffsetY,
-                    _ => mode,
-                };
-                match modifier {
-                    ast::OperandModifier::None => Ok(IRParam::Resolved(corrected_mode, OpParam::Byte(val))),
-                    _ => Err(AssemblerError(tag, "can't take high/low byte of a single byte".into()).into()),
-                }
-            }
-            ast::Number::Word(val) => match modifier {
-                ast::OperandModifier::None => Ok(IRParam::Resolved(mode, OpParam::Word(val))),
-                ast::OperandModifier::HighByte => Ok(IRParam::Resolved(mode, OpParam::Byte((val >> 8) as u8))),
-                ast::OperandModifier::LowByte => Ok(IRParam::Resolved(mode, OpParam::Byte(val as u8))),
-            },
-            ast::Number::Invalid(_val) => {
-                Err(AssemblerError(tag, "number not within 8-bit or 16-bit bounds".into()).into())
-            }
+fn zero_page_mode(mode: OpAddressMode) -> OpAddressMode {
+    match mode {
+        OpAddressMode::Absolute => OpAddressMode::ZeroPage,
+        OpAddressMode::AbsoluteOffsetX => OpAddressMode::ZeroPageOffsetX,
+        OpAddressMode::AbsoluteOffsetY => OpAddressMode::ZeroPageOffsetY,
+        _ => mode,
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum ScopeKind {
+    Scope,
+    Proc,
+}
+
+impl ScopeKind {
+    fn directive(&self) -> &'static str {
+        match *self {
+            ScopeKind::Scope => ".scope",
+            ScopeKind::Proc => ".proc",
+        }
+    }
+
+    fn end_directive(&self) -> &'static str {
+        match *self {
+            ScopeKind::Scope => ".endscope",
+            ScopeKind::Proc => ".endproc",
         }
     }
 }
 
+/// How a `.export`, `.import` or `.global` shares a name with other units
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum Linkage {
+    Export,
+    Import,
+    Global,
+}
+
+/// A name declared by `.export`, `.import` or `.global`
+struct LinkName {
+    tag: SrcTag,
+    linkage: Linkage,
+    /// Qualified name
+    name: Arc<String>,
+    zero_page: bool,
+}
+
+/// A `.scope` or `.proc` that hasn't been ended yet
+struct OpenScope {
+    tag: SrcTag,
+    kind: ScopeKind,
+    /// Scope to return to once this one ends
+    outer: Arc<String>,
+}
+
 struct IRBuilder {
     blocks: Vec<IRBlock>,
+    /// Tag of the `.rorg` whose region is being built, if any
+    relocation: Option<SrcTag>,
+    /// Scopes that are open, innermost last
+    scopes: Vec<OpenScope>,
+    /// Qualified name of the innermost open scope
+    scope: Arc<String>,
+    /// Segment that new blocks are placed in
+    segment: Arc<String>,
+    /// Constants whose values are known before addresses are assigned
+    constants: HashMap<Arc<String>, i64>,
+    /// Labels and constants defined so far, which can't be used as variables
+    fixed_symbols: HashMap<Arc<String>, SrcTag>,
+    /// Current expression for each `.set` variable, which is substituted wherever the variable is used
+    variables: HashMap<Arc<String>, (SrcTag, ast::Expr)>,
+    /// Number of hidden constants made to hold `*` for variables
+    anchors: usize,
+    /// Names shared with other units, in the order that they're declared
+    link_names: Vec<LinkName>,
+    /// Procs and segments marked with `.removable`
+    removable_procs: HashSet<Arc<String>>,
+    removable_segments: HashSet<Arc<String>>,
+    /// iNES header fields set so far
+    ines: InesOptions,
 }
 
 impl IRBuilder {
     pub fn new() -> IRBuilder {
-        IRBuilder { blocks: Vec::new() }
+        IRBuilder {
+            blocks: Vec::new(),
+            relocation: None,
+            scopes: Vec::new(),
+            scope: Arc::new(String::new()),
+            segment: Arc::new(DEFAULT_SEGMENT.into()),
+            constants: HashMap::new(),
+            fixed_symbols: HashMap::new(),
+            variables: HashMap::new(),
+            anchors: 0,
+            link_names: Vec::new(),
+            removable_procs: HashSet::new(),
+            removable_segments: HashSet::new(),
+            ines: InesOptions::default(),
+        }
+    }
+
+    /// Defines a label or constant in the current scope and returns its qualified name
+    pub fn define_fixed(&mut self, tag: SrcTag, name: &Arc<String>) -> Result<Arc<String>, Diagnostic> {
+        let name = self.qualify_definition(tag, name)?;
+        if let Some(&(variable_tag, _)) = self.variables.get(&name) {
+            return Err(error_at(
+                DiagnosticCode::DuplicateSymbol,
+                tag,
+                format!("\"{}\" is already a `.set` variable", name),
+            ).with_label(variable_tag, "first set here"));
+        }
+        self.fixed_symbols.insert(Arc::clone(&name), tag);
+        Ok(name)
+    }
+
+    /// Defines a constant in the current scope, working out its value now if it only depends on earlier constants
+    pub fn define_constant(
+        &mut self,
+        tag: SrcTag,
+        name: &Arc<String>,
+        expr: ast::Expr,
+    ) -> Result<Arc<String>, Diagnostic> {
+        let name = self.define_fixed(tag, name)?;
+        if let Some(value) = self.constant_value(&expr) {
+            self.constants.insert(Arc::clone(&name), value);
+        }
+        self.current_block(tag)
+            .add_constant(IRConstant::new(tag, Arc::clone(&name), expr));
+        Ok(name)
     }
 
-    pub fn new_block(&mut self, position: Option<u16>, label: Option<Arc<String>>) {
-        self.blocks.push(IRBlock::new(position, label));
+    pub fn set_variable(&mut self, tag: SrcTag, name: &Arc<String>, expr: &ast::Expr) -> Result<(), Diagnostic> {
+        // Assign to a variable that's visible from here, or else define a new one in the current scope
+        let name = match lookup_candidates(&self.scope, name)
+            .into_iter()
+            .find(|candidate| self.variables.contains_key(candidate))
+        {
+            Some(name) => name,
+            None => self.qualify_definition(tag, name)?,
+        };
+        if let Some(fixed_tag) = self.fixed_symbols.get(&name) {
+            return Err(error_at(
+                DiagnosticCode::DuplicateSymbol,
+                tag,
+                format!("\"{}\" is a label or constant, so it can't be changed with `.set`", name),
+            ).with_label(*fixed_tag, "defined here"));
+        }
+
+        let mut expr = self.substitute_names(expr, true);
+        // `*` has to keep referring to this point, so hold it in a hidden constant defined here
+        if expr.contains_program_counter() {
+            let anchor = Arc::new(format!("*{}", self.anchors));
+            self.anchors += 1;
+            self.current_block(tag).add_constant(IRConstant::new(
+                tag,
+                Arc::clone(&anchor),
+                ast::Expr::ProgramCounter(tag),
+            ));
+            expr = replace_program_counter(&expr, &Arc::new(format!("::{}", anchor)));
+        }
+        // Fold values that are already known so that repeated `.set`s don't build ever larger expressions
+        if let Some(value) = self.constant_value(&expr) {
+            expr = ast::Expr::from_value(expr.tag(), value);
+        }
+        self.variables.insert(name, (tag, expr));
+        Ok(())
     }
 
-    pub fn current_block(&mut self) -> &mut IRBlock {
+    pub fn qualify_definition(&self, tag: SrcTag, name: &Arc<String>) -> Result<Arc<String>, Diagnostic> {
+        if name.contains("::") {
+            return Err(error_at(
+                DiagnosticCode::QualifiedDefinition,
+                tag,
+                format!("\"{}\" can only be defined inside its scope", name),
+            ));
+        }
+        Ok(qualify(&self.scope, name))
+    }
+
+    /// Evaluates an expression if it only depends on numbers and constants that are already known
+    pub fn constant_value(&self, expr: &ast::Expr) -> Option<i64> {
+        let mut symbols = ConstantSymbols {
+            constants: &self.constants,
+            defined: &self.fixed_symbols,
+            scope: &self.scope,
+        };
+        eval::evaluate(expr, &mut symbols).ok()
+    }
+
+    /// Replaces `.set` variables in an expression with their current values
+    pub fn substitute(&self, expr: &ast::Expr) -> ast::Expr {
+        self.substitute_names(expr, false)
+    }
+
+    /// Substitutes variables, and when `anchor` is set also makes names of labels and constants defined so far
+    /// global, so that a variable's value keeps its meaning when it's used in another scope
+    fn substitute_names(&self, expr: &ast::Expr, anchor: bool) -> ast::Expr {
+        match *expr {
+            ast::Expr::Name(tag, ref name) => {
+                for candidate in lookup_candidates(&self.scope, name) {
+                    if self.fixed_symbols.contains_key(&candidate) {
+                        if anchor {
+                            return ast::Expr::Name(tag, Arc::new(format!("::{}", candidate)));
+                        }
+                        break;
+                    }
+                    if let Some(&(_, ref value)) = self.variables.get(&candidate) {
+                        return value.clone();
+                    }
+                }
+                expr.clone()
+            }
+            ast::Expr::Unary(tag, operator, ref operand) => {
+                ast::Expr::Unary(tag, operator, Box::new(self.substitute_names(operand, anchor)))
+            }
+            ast::Expr::Binary(tag, operator, ref left, ref right) => ast::Expr::Binary(
+                tag,
+                operator,
+                Box::new(self.substitute_names(left, anchor)),
+                Box::new(self.substitute_names(right, anchor)),
+            ),
+            ast::Expr::Defined(tag, ref name) => {
+                let is_variable = lookup_candidates(&self.scope, name)
+                    .iter()
+                    .any(|candidate| self.variables.contains_key(candidate));
+                if is_variable {
+                    ast::Expr::Number(tag, ast::Number::Byte(1))
+                } else {
+                    expr.clone()
+                }
+            }
+            ast::Expr::Call(tag, function, ref arguments) => ast::Expr::Call(
+                tag,
+                function,
+                arguments
+                    .iter()
+                    .map(|argument| self.substitute_names(argument, anchor))
+                    .collect(),
+            ),
+            ast::Expr::Number(_, _) | ast::Expr::ProgramCounter(_) | ast::Expr::SizeOf(_, _) => expr.clone(),
+        }
+    }
+
+    pub fn substitute_operand(&self, operand: &ast::Operand) -> ast::Operand {
+        use ast::Operand::*;
+        match *operand {
+            None => None,
+            Immediate(ref expr) => Immediate(self.substitute(expr)),
+            Address(ref expr) => Address(self.substitute(expr)),
+            AbsoluteX(ref expr) => AbsoluteX(self.substitute(expr)),
+            AbsoluteY(ref expr) => AbsoluteY(self.substitute(expr)),
+            Indirect(ref expr) => Indirect(self.substitute(expr)),
+            IndirectX(ref expr) => IndirectX(self.substitute(expr)),
+            IndirectY(ref expr) => IndirectY(self.substitute(expr)),
+        }
+    }
+
+    /// Switches to the given scope, returning the one that was current
+    pub fn enter_scope(&mut self, scope: Arc<String>) -> Arc<String> {
+        ::std::mem::replace(&mut self.scope, scope)
+    }
+
+    pub fn open_scope(&mut self, tag: SrcTag, kind: ScopeKind, name: &Arc<String>) -> Result<(), Diagnostic> {
+        let scope = self.qualify_definition(tag, name)?;
+        let outer = self.enter_scope(scope);
+        self.scopes.push(OpenScope {
+            tag: tag,
+            kind: kind,
+            outer: outer,
+        });
+        if kind == ScopeKind::Scope {
+            self.new_block(tag, None, None);
+        }
+        Ok(())
+    }
+
+    pub fn close_scope(&mut self, tag: SrcTag, kind: ScopeKind) -> Result<(), Diagnostic> {
+        match self.scopes.last() {
+            Some(open) if open.kind == kind => {}
+            Some(open) => {
+                return Err(error_at(
+                    DiagnosticCode::UnbalancedScope,
+                    tag,
+                    format!("`{}` doesn't match the open `{}`", kind.end_directive(), open.kind.directive()),
+                ).with_label(open.tag, "scope starts here"))
+            }
+            None => {
+                return Err(error_at(
+                    DiagnosticCode::UnbalancedScope,
+                    tag,
+                    format!("`{}` without a matching `{}`", kind.end_directive(), kind.directive()),
+                ))
+            }
+        }
+        let open = self.scopes.pop().unwrap();
+        self.scope = open.outer;
+        self.new_block(tag, None, None);
+        Ok(())
+    }
+
+    pub fn new_block(&mut self, tag: SrcTag, position: Option<u16>, label: Option<Arc<String>>) {
+        let placement = if position.is_some() {
+            IRPlacement::Org
+        } else {
+            IRPlacement::Normal
+        };
+        self.push_block(tag, placement, position, label);
+    }
+
+    pub fn start_relocation(&mut self, tag: SrcTag, position: u16) {
+        self.relocation = Some(tag);
+        self.push_block(tag, IRPlacement::Relocate, Some(position), None);
+    }
+
+    pub fn end_relocation(&mut self, tag: SrcTag) {
+        self.relocation = None;
+        self.push_block(tag, IRPlacement::EndRelocate, None, None);
+    }
+
+    pub fn start_segment(&mut self, tag: SrcTag, segment: &Arc<String>) {
+        self.segment = Arc::clone(segment);
+        self.new_block(tag, None, None);
+    }
+
+    fn push_block(&mut self, tag: SrcTag, placement: IRPlacement, position: Option<u16>, label: Option<Arc<String>>) {
+        let scope = Arc::clone(&self.scope);
+        let segment = Arc::clone(&self.segment);
+        self.blocks
+            .push(IRBlock::new(tag, placement, scope, segment, position, label));
+    }
+
+    /// Returns the block being built, starting one at the given tag if there isn't one yet
+    pub fn current_block(&mut self, tag: SrcTag) -> &mut IRBlock {
         if self.blocks.is_empty() {
-            self.new_block(None, None);
+            self.new_block(tag, None, None);
         }
         let cur = self.blocks.len() - 1;
         &mut self.blocks[cur]
     }
 
-    pub fn build(self) -> IR {
-        IR::new(self.blocks)
+    pub fn declare_links(
+        &mut self,
+        names: &[(SrcTag, Arc<String>)],
+        linkage: Linkage,
+        zero_page: bool,
+    ) -> Result<(), Diagnostic> {
+        for &(tag, ref name) in names {
+            let name = self.qualify_definition(tag, name)?;
+            self.link_names.push(LinkName {
+                tag: tag,
+                linkage: linkage,
+                name: name,
+                zero_page: zero_page,
+            });
+        }
+        Ok(())
+    }
+
+    /// True if the expression is a name imported as zero page, or such a name plus or minus an offset
+    pub fn is_zero_page_import(&self, expr: &ast::Expr) -> bool {
+        let name = match *expr {
+            ast::Expr::Name(_, ref name) => name,
+            ast::Expr::Binary(_, ast::BinaryOperator::Add, ref left, _)
+            | ast::Expr::Binary(_, ast::BinaryOperator::Subtract, ref left, _) => match **left {
+                ast::Expr::Name(_, ref name) => name,
+                _ => return false,
+            },
+            _ => return false,
+        };
+        for candidate in lookup_candidates(&self.scope, name) {
+            if self.fixed_symbols.contains_key(&candidate) {
+                return false;
+            }
+            let import = self.link_names
+                .iter()
+                .find(|link| link.name == candidate && link.linkage != Linkage::Export);
+            if let Some(import) = import {
+                return import.zero_page;
+            }
+        }
+        false
+    }
+
+    /// Works out what the unit exports and imports now that every name it defines is known. `.global` names
+    /// are exported if they're defined, and imported otherwise.
+    fn link(&self, diagnostics: &mut Vec<Diagnostic>) -> (Vec<IRExport>, Vec<IRImport>) {
+        let mut exports: Vec<IRExport> = Vec::new();
+        let mut imports: Vec<IRImport> = Vec::new();
+        for link in &self.link_names {
+            let definition = self.fixed_symbols.get(&link.name);
+            let export = match (link.linkage, definition) {
+                (Linkage::Export, None) => {
+                    diagnostics.push(error_at(
+                        DiagnosticCode::UndefinedExport,
+                        link.tag,
+                        format!("\"{}\" is exported, but never defined", link.name),
+                    ));
+                    continue;
+                }
+                (Linkage::Import, Some(&definition)) => {
+                    diagnostics.push(error_at(
+                        DiagnosticCode::DuplicateSymbol,
+                        definition,
+                        format!("\"{}\" is imported, so it can't also be defined", link.name),
+                    ).with_label(link.tag, "imported here"));
+                    continue;
+                }
+                (Linkage::Export, Some(_)) => true,
+                (Linkage::Import, None) => false,
+                (Linkage::Global, definition) => definition.is_some(),
+            };
+            if export {
+                if !exports.iter().any(|export| export.name == link.name) {
+                    exports.push(IRExport::new(link.tag, Arc::clone(&link.name), Arc::clone(&link.name)));
+                }
+            } else if !imports.iter().any(|import| import.name == link.name) {
+                imports.push(IRImport::new(
+                    link.tag,
+                    Arc::clone(&link.name),
+                    Arc::clone(&link.name),
+                    link.zero_page,
+                ));
+            }
+        }
+        (exports, imports)
+    }
+
+    /// Marks the innermost `.proc` as removable, or the current segment when outside of any scope
+    pub fn mark_removable(&mut self, tag: SrcTag) -> Result<(), Diagnostic> {
+        match self.scopes.last().map(|open| open.kind) {
+            Some(ScopeKind::Proc) => {
+                self.removable_procs.insert(Arc::clone(&self.scope));
+            }
+            None => {
+                self.removable_segments.insert(Arc::clone(&self.segment));
+            }
+            Some(ScopeKind::Scope) => {
+                return Err(error_at(
+                    DiagnosticCode::MisplacedRemovable,
+                    tag,
+                    "`.removable` must be inside a `.proc`, or outside of any scope to mark the segment",
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets an iNES header field; a field that's set again takes the later value
+    pub fn set_ines(&mut self, tag: SrcTag, setting: &ast::InesSetting) -> Result<(), Diagnostic> {
+        let limit = |value: u16, max: u16, what: &str| {
+            if value > max {
+                Err(error_at(
+                    DiagnosticCode::NumberOutOfRange,
+                    tag,
+                    format!("{} can't be more than {}", what, max),
+                ))
+            } else {
+                Ok(value)
+            }
+        };
+        match *setting {
+            ast::InesSetting::PrgBanks(number) => {
+                let banks = word_argument(tag, number, "PRG bank count")?;
+                self.ines.prg_banks = Some(limit(banks, 0xEFF, "PRG bank count")?);
+            }
+            ast::InesSetting::ChrBanks(number) => {
+                let banks = word_argument(tag, number, "CHR bank count")?;
+                self.ines.chr_banks = Some(limit(banks, 0xEFF, "CHR bank count")?);
+            }
+            ast::InesSetting::Mapper(number) => {
+                let mapper = word_argument(tag, number, "mapper")?;
+                self.ines.mapper = Some(limit(mapper, 0xFFF, "mapper")?);
+            }
+            ast::InesSetting::Mirroring(ref name) => match Mirroring::from_name(name) {
+                Some(mirroring) => self.ines.mirroring = Some(mirroring),
+                None => {
+                    let names: Vec<&str> = Mirroring::all().iter().map(Mirroring::name).collect();
+                    return Err(error_at(
+                        DiagnosticCode::UnknownMirroring,
+                        tag,
+                        format!("unknown mirroring `{}`", name),
+                    ).with_note(format!("expected one of: {}", names.join(", "))));
+                }
+            },
+            ast::InesSetting::Battery => self.ines.battery = Some(true),
+        }
+        Ok(())
+    }
+
+    /// Returns what a block would be removed along with: its innermost removable proc, or else its segment
+    fn removable(&self, block: &IRBlock) -> Option<IRRemovable> {
+        let mut scope = &block.scope[..];
+        while !scope.is_empty() {
+            if let Some(proc_name) = self.removable_procs.iter().find(|proc_name| ***proc_name == scope) {
+                return Some(IRRemovable::Proc(Arc::clone(proc_name)));
+            }
+            scope = match scope.rfind("::") {
+                Some(index) => &scope[..index],
+                None => "",
+            };
+        }
+        self.removable_segments
+            .get(&block.segment)
+            .map(|segment| IRRemovable::Segment(Arc::clone(segment)))
+    }
+
+    pub fn build(mut self, diagnostics: &mut Vec<Diagnostic>) -> IR {
+        let (exports, imports) = self.link(diagnostics);
+        let removable: Vec<Option<IRRemovable>> = self.blocks.iter().map(|block| self.removable(block)).collect();
+        for (block, removable) in self.blocks.iter_mut().zip(removable) {
+            block.removable = removable;
+        }
+        let mut ir = IR::new(self.blocks, exports, imports, BTreeMap::new());
+        ir.ines = self.ines;
+        ir
     }
 }
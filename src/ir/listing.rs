@@ -0,0 +1,46 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::collections::BTreeMap;
+
+use ir::{IR, MODULE_PREFIX};
+
+/// Lists the value of every label and constant, grouped by the scope that it was defined in. The names that linked
+/// objects define are grouped by their source file.
+pub fn symbol_listing(ir: &IR) -> String {
+    let mut scopes: BTreeMap<&str, Vec<(&str, i64)>> = BTreeMap::new();
+    for (name, value) in &ir.symbols {
+        let (scope, local) = match name.rfind("::") {
+            Some(index) => (&name[..index], &name[index + 2..]),
+            None => ("", &name[..]),
+        };
+        scopes.entry(scope).or_insert_with(Vec::new).push((local, *value));
+    }
+
+    let mut listing = String::new();
+    for (scope, symbols) in scopes {
+        if !listing.is_empty() {
+            listing.push('\n');
+        }
+        if scope.is_empty() {
+            listing.push_str("; global\n");
+        } else {
+            let scope = if scope.starts_with(MODULE_PREFIX) { &scope[1..] } else { scope };
+            listing.push_str(&format!("; {}\n", scope));
+        }
+        for (name, value) in symbols {
+            if value >= 0 && value <= 0xFFFF {
+                listing.push_str(&format!("{} = ${:04X}\n", name, value));
+            } else {
+                listing.push_str(&format!("{} = {}\n", name, value));
+            }
+        }
+    }
+    listing
+}
@@ -15,7 +15,10 @@ pub struct SourceMapEntry {
     unit: usize,
     offset: usize,
     line: usize,
+    /// Address the instruction runs at
     address: u16,
+    /// Address the instruction is loaded at, which differs from `address` inside a `.rorg` region
+    load_address: u16,
 }
 
 #[derive(Serialize)]
@@ -50,9 +53,16 @@ impl AddEntries for IR {
 
 impl AddEntries for IRBlock {
     fn add_entries(&self, src_units: &SrcUnits, source_map: &mut SourceMap) {
+        let first_entry = source_map.entries.len();
         for chunk in &self.chunks {
             chunk.add_entries(src_units, source_map);
         }
+
+        let position = self.position.unwrap();
+        let load_position = self.load_position.unwrap();
+        for entry in &mut source_map.entries[first_entry..] {
+            entry.load_address = load_position.wrapping_add(entry.address.wrapping_sub(position));
+        }
     }
 }
 
@@ -72,10 +82,9 @@ impl AddEntries for IROp {
         source_map.entries.push(SourceMapEntry {
             unit: self.tag.unit,
             offset: self.tag.offset,
-            line: (&src_units.unit(self.tag.unit).source[0..self.tag.offset])
-                .chars()
-                .fold(1, |acc, chr| if chr == '\n' { acc + 1 } else { acc }),
+            line: src_units.unit(self.tag.unit).row(self.tag.offset),
             address: self.position,
+            load_address: self.position,
         });
     }
 }
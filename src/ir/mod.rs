@@ -8,29 +8,81 @@
 //
 
 use hassel_lib6502::{OpAddressMode, OpCode, OpParam};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
+use ast;
+use format::ines::InesOptions;
 use src_tag::SrcTag;
 
+mod eval;
+mod op_serde;
 pub(crate) mod gen;
+pub(crate) mod listing;
 pub(crate) mod map;
+pub(crate) mod prune;
 
-#[derive(Debug)]
+/// Returns the name that a qualified name was written as in its unit, without the module that it was linked into
+pub fn unlinked_name(name: &str) -> &str {
+    if name.starts_with(MODULE_PREFIX) {
+        if let Some(index) = name.find("::") {
+            return &name[index + 2..];
+        }
+    }
+    name
+}
+
+/// Qualifies a name with the scope that it's defined in
+pub fn qualify(scope: &str, name: &str) -> Arc<String> {
+    if scope.is_empty() {
+        Arc::new(name.into())
+    } else {
+        Arc::new(format!("{}::{}", scope, name))
+    }
+}
+
+/// Linked objects each get a module scope, named `@` and the object's source file, that holds everything they
+/// define. Lookups stop at the module scope, so other objects are only reached through imports.
+pub const MODULE_PREFIX: char = '@';
+
+/// Returns the qualified names that a reference could mean, from the innermost scope outwards. A leading `::`
+/// refers to the global scope.
+pub fn lookup_candidates(scope: &str, name: &str) -> Vec<Arc<String>> {
+    if name.starts_with("::") {
+        return vec![Arc::new(name[2..].into())];
+    }
+    let mut candidates = Vec::new();
+    let mut scope = scope;
+    loop {
+        candidates.push(qualify(scope, name));
+        if scope.is_empty() || (scope.starts_with(MODULE_PREFIX) && !scope.contains("::")) {
+            return candidates;
+        }
+        scope = match scope.rfind("::") {
+            Some(index) => &scope[..index],
+            None => "",
+        };
+    }
+}
+
+/// Operand of an op. Unresolved operands are relocations, which are evaluated once addresses are assigned.
+#[derive(Debug, Serialize, Deserialize)]
 pub enum IRParam {
-    Resolved(OpAddressMode, OpParam),
-    Unresolved(OpAddressMode, SrcTag, Arc<String>),
-    UnresolvedLowByte(OpAddressMode, SrcTag, Arc<String>),
-    UnresolvedHighByte(OpAddressMode, SrcTag, Arc<String>),
+    Resolved(
+        #[serde(with = "op_serde::address_mode")] OpAddressMode,
+        #[serde(with = "op_serde::param")] OpParam,
+    ),
+    /// Word operand, or the target of a branch when the mode is `PCOffset`
+    Unresolved(#[serde(with = "op_serde::address_mode")] OpAddressMode, ast::Expr),
+    /// Byte operand, such as the low or high byte of an address
+    UnresolvedByte(#[serde(with = "op_serde::address_mode")] OpAddressMode, ast::Expr),
 }
 
 impl IRParam {
     pub fn mode(&self) -> OpAddressMode {
         use self::IRParam::*;
         match *self {
-            Resolved(mode, _)
-            | Unresolved(mode, _, _)
-            | UnresolvedLowByte(mode, _, _)
-            | UnresolvedHighByte(mode, _, _) => mode,
+            Resolved(mode, _) | Unresolved(mode, _) | UnresolvedByte(mode, _) => mode,
         }
     }
 
@@ -38,9 +90,8 @@ impl IRParam {
         use self::IRParam::*;
         match self {
             Resolved(_, param) => Resolved(mode, param),
-            Unresolved(_, tag, name) => Unresolved(mode, tag, name),
-            UnresolvedLowByte(_, tag, name) => UnresolvedLowByte(mode, tag, name),
-            UnresolvedHighByte(_, tag, name) => UnresolvedHighByte(mode, tag, name),
+            Unresolved(_, expr) => Unresolved(mode, expr),
+            UnresolvedByte(_, expr) => UnresolvedByte(mode, expr),
         }
     }
 
@@ -52,46 +103,230 @@ impl IRParam {
     }
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Serialize, Deserialize)]
 pub struct IROp {
     pub tag: SrcTag,
+    #[serde(with = "op_serde::op_code")]
     pub code: &'static OpCode,
     pub param: IRParam,
     pub position: u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IRFillKind {
+    /// Fills up to, but not including, the given address
+    PadTo(u16),
+    /// Fills up to the next multiple of the given alignment
+    Align(u16),
+    /// Fills the given number of bytes
+    Repeat(u16),
+    /// Reserves the given number of bytes, which are only filled when the region produces output
+    Reserve(u16),
+}
+
+/// Run of identical bytes whose length can depend on where it is placed
+#[derive(Debug, new, Serialize, Deserialize)]
+pub struct IRFill {
+    pub tag: SrcTag,
+    pub kind: IRFillKind,
+    pub value: u8,
+    pub length: u16,
+}
+
+/// Condition from `.assert` that must hold once every label has its final address
+#[derive(Debug, new, Serialize, Deserialize)]
+pub struct IRAssert {
+    pub tag: SrcTag,
+    pub expr: ast::Expr,
+    pub message: Option<Arc<String>>,
+}
+
+/// Loader vector that a `.run` or `.init` address is written to
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IRRunKind {
+    /// Jumped to once the whole file is loaded
+    Run,
+    /// Called as soon as the vector is loaded
+    Init,
+}
+
+/// Address from `.run` or `.init` for the loader to call, which is evaluated once addresses are known
+#[derive(Debug, new, Serialize, Deserialize)]
+pub struct IRRunAddress {
+    pub tag: SrcTag,
+    pub kind: IRRunKind,
+    pub expr: ast::Expr,
+    #[new(default)]
+    pub address: u16,
+}
+
+/// Message from `.print`, which is reported as a note once addresses are known
+#[derive(Debug, new, Serialize, Deserialize)]
+pub struct IRPrint {
+    pub tag: SrcTag,
+    pub items: Vec<ast::PrintItem>,
+}
+
+/// Values from `.byte` or `.word`, which are evaluated once addresses are known
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IRData {
+    pub tag: SrcTag,
+    /// Size of each value in bytes
+    pub width: usize,
+    pub exprs: Vec<ast::Expr>,
+    pub bytes: Vec<u8>,
+}
+
+/// Immutable `name = expr` definition, which is evaluated when the name is first used
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
+pub struct IRConstant {
+    pub tag: SrcTag,
+    pub name: Arc<String>,
+    pub expr: ast::Expr,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub enum IRChunk {
     Op(IROp),
-    Bytes(Vec<u8>),
+    Data(IRData),
     Vector(SrcTag, Arc<String>, u16),
+    /// Zero-length check that the following pointer doesn't start at `$xxFF`
+    PointerCheck(SrcTag),
+    Fill(IRFill),
+    /// Zero-length assertion
+    Assert(IRAssert),
+    /// Zero-length constant definition, so that `*` refers to where it was defined
+    Constant(IRConstant),
+    /// Zero-length message
+    Print(IRPrint),
+    /// Zero-length address for the loader to call
+    RunAddress(IRRunAddress),
 }
 
 impl IRChunk {
     fn len(&self) -> usize {
         match *self {
             IRChunk::Op(ref op) => op.code.len as usize,
-            IRChunk::Bytes(ref bytes) => bytes.len(),
+            IRChunk::Data(ref data) => data.exprs.len() * data.width,
             IRChunk::Vector(_, _, _) => 2,
+            IRChunk::PointerCheck(_)
+            | IRChunk::Assert(_)
+            | IRChunk::Constant(_)
+            | IRChunk::Print(_)
+            | IRChunk::RunAddress(_) => 0,
+            IRChunk::Fill(ref fill) => fill.length as usize,
         }
     }
+
+    /// Whether the chunk holds data, as opposed to spacing or reserved space
+    fn is_data(&self) -> bool {
+        match *self {
+            IRChunk::Op(_) | IRChunk::Data(_) | IRChunk::Vector(_, _, _) => true,
+            IRChunk::PointerCheck(_)
+            | IRChunk::Assert(_)
+            | IRChunk::Constant(_)
+            | IRChunk::Print(_)
+            | IRChunk::RunAddress(_) => false,
+            IRChunk::Fill(ref fill) => match fill.kind {
+                IRFillKind::Repeat(_) => true,
+                IRFillKind::PadTo(_) | IRFillKind::Align(_) | IRFillKind::Reserve(_) => false,
+            },
+        }
+    }
+}
+
+/// How a block is placed relative to the block before it
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IRPlacement {
+    /// Follows the previous block
+    Normal,
+    /// Placed at its position by `.org`, which starts a new region
+    Org,
+    /// Starts a `.rorg` region, which runs at its position but is loaded after the previous block
+    Relocate,
+    /// Ends a `.rorg` region, so that the block runs where it is loaded again
+    EndRelocate,
+}
+
+/// Code that the linker leaves out when nothing that it keeps refers to it
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum IRRemovable {
+    /// A `.proc` marked with `.removable`, by qualified name
+    Proc(Arc<String>),
+    /// The part of a segment marked with `.removable` that's in one unit
+    Segment(Arc<String>),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum IRRelocationKind {
+    /// Both bytes of an address
+    Word,
+    /// Low byte of an address
+    Low,
+    /// High byte of an address, along with the low byte, which relocating can carry from
+    High(u8),
+}
+
+/// Place in a block's bytes that holds an address, which has to be adjusted if the segment that the address is
+/// in is moved
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
+pub struct IRRelocation {
+    /// Offset of the address from the start of the block
+    pub offset: u16,
+    pub kind: IRRelocationKind,
+    /// Segment that the address is in
+    pub segment: Arc<String>,
+    /// Import that the address is relative to instead, when no unit exports it
+    #[new(default)]
+    #[serde(default)]
+    pub import: Option<Arc<String>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct IRBlock {
+    /// Label or directive that started the block
+    pub tag: SrcTag,
+    pub placement: IRPlacement,
+    /// Qualified name of the scope the block is in, such as `Outer::inner`, or empty for the global scope
+    pub scope: Arc<String>,
+    /// Segment that the block is placed in when there's a memory layout
+    pub segment: Arc<String>,
+    /// Address that the block runs at, which its labels refer to
     pub position: Option<u16>,
+    /// Address that the block's bytes are placed at in the output
+    pub load_position: Option<u16>,
+    /// Qualified name of the label that starts the block
     pub label: Option<Arc<String>>,
+    /// The removable proc or segment that the block belongs to, if any
+    pub removable: Option<IRRemovable>,
     pub chunks: Vec<IRChunk>,
     pub length: u16,
+    /// Addresses in the block's bytes, which are only recorded for relocatable targets
+    #[serde(default)]
+    pub relocations: Vec<IRRelocation>,
 }
 
 impl IRBlock {
-    fn new(position: Option<u16>, label: Option<Arc<String>>) -> IRBlock {
+    pub(crate) fn new(
+        tag: SrcTag,
+        placement: IRPlacement,
+        scope: Arc<String>,
+        segment: Arc<String>,
+        position: Option<u16>,
+        label: Option<Arc<String>>,
+    ) -> IRBlock {
         IRBlock {
+            tag: tag,
+            placement: placement,
+            scope: scope,
+            segment: segment,
             position: position,
+            load_position: None,
             label: label,
+            removable: None,
             chunks: Vec::new(),
             length: 0,
+            relocations: Vec::new(),
         }
     }
 
@@ -99,17 +334,112 @@ impl IRBlock {
         self.chunks.push(IRChunk::Op(op));
     }
 
-    fn add_bytes(&mut self, bytes: Vec<u8>) {
-        self.chunks.push(IRChunk::Bytes(bytes));
+    fn add_data(&mut self, tag: SrcTag, width: usize, exprs: Vec<ast::Expr>) {
+        self.chunks.push(IRChunk::Data(IRData {
+            tag: tag,
+            width: width,
+            exprs: exprs,
+            bytes: Vec::new(),
+        }));
+    }
+
+    fn add_constant(&mut self, constant: IRConstant) {
+        self.chunks.push(IRChunk::Constant(constant));
+    }
+
+    fn add_print(&mut self, print: IRPrint) {
+        self.chunks.push(IRChunk::Print(print));
     }
 
     fn add_vector(&mut self, tag: SrcTag, label: &Arc<String>) {
         self.chunks
             .push(IRChunk::Vector(tag, Arc::clone(&label), 0));
     }
+
+    fn add_pointer_check(&mut self, tag: SrcTag) {
+        self.chunks.push(IRChunk::PointerCheck(tag));
+    }
+
+    fn add_assert(&mut self, assert: IRAssert) {
+        self.chunks.push(IRChunk::Assert(assert));
+    }
+
+    fn add_run_address(&mut self, run_address: IRRunAddress) {
+        self.chunks.push(IRChunk::RunAddress(run_address));
+    }
+
+    fn add_fill(&mut self, tag: SrcTag, kind: IRFillKind, value: u8) {
+        self.chunks.push(IRChunk::Fill(IRFill::new(tag, kind, value, 0)));
+    }
+
+    pub fn has_data(&self) -> bool {
+        self.chunks.iter().any(IRChunk::is_data)
+    }
+
+    /// Blocks that only hold constants, assertions, prints and run addresses don't take any space, so they don't need
+    /// to be placed
+    pub fn needs_placement(&self) -> bool {
+        self.label.is_some() || self.chunks.iter().any(|chunk| match *chunk {
+            IRChunk::Constant(_) | IRChunk::Assert(_) | IRChunk::Print(_) | IRChunk::RunAddress(_) => false,
+            _ => true,
+        })
+    }
+}
+
+/// Name that a unit makes visible to other units with `.export` or `.global`
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
+pub struct IRExport {
+    pub tag: SrcTag,
+    /// Name that other units import it as
+    pub name: Arc<String>,
+    /// Qualified name of the exported label or constant, which includes the module once linked
+    pub local: Arc<String>,
+    /// Segment that the value is an address in, which is only recorded for relocatable targets
+    #[new(default)]
+    #[serde(default)]
+    pub segment: Option<Arc<String>>,
+}
+
+/// Name that a unit uses from another unit, declared with `.import` or `.global`
+#[derive(Debug, Clone, new, Serialize, Deserialize)]
+pub struct IRImport {
+    pub tag: SrcTag,
+    /// Name that another unit exports
+    pub name: Arc<String>,
+    /// Qualified name that the unit refers to it by, which includes the module once linked
+    pub local: Arc<String>,
+    /// Whether references to it were assembled with zero page addressing
+    pub zero_page: bool,
 }
 
-#[derive(Debug, new)]
+#[derive(Debug, new, Serialize, Deserialize)]
 pub struct IR {
     pub blocks: Vec<IRBlock>,
+    pub exports: Vec<IRExport>,
+    pub imports: Vec<IRImport>,
+    /// Value of every label and constant by qualified name, once resolved
+    pub symbols: BTreeMap<Arc<String>, i64>,
+    /// iNES header settings given by the program
+    #[new(default)]
+    #[serde(default)]
+    pub ines: InesOptions,
+}
+
+impl IR {
+    /// Returns whether each block produces output. A region started by `.org` that only reserves
+    /// space, such as a RAM layout, advances the location counter without producing output.
+    pub fn block_outputs(&self) -> Vec<bool> {
+        let mut outputs = vec![false; self.blocks.len()];
+        let mut region_start = 0;
+        for index in 1..self.blocks.len() + 1 {
+            if index == self.blocks.len() || self.blocks[index].placement == IRPlacement::Org {
+                let has_data = self.blocks[region_start..index].iter().any(IRBlock::has_data);
+                for output in &mut outputs[region_start..index] {
+                    *output = has_data;
+                }
+                region_start = index;
+            }
+        }
+        outputs
+    }
 }
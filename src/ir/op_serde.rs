@@ -0,0 +1,102 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+//! Serializes the `hassel_lib6502` types that the IR holds, for use with `#[serde(with = "...")]`
+
+use hassel_lib6502::{OpAddressMode, OpClass, OpCode, OpParam};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+
+fn mode_name(mode: OpAddressMode) -> String {
+    format!("{:?}", mode)
+}
+
+fn mode_from_name<E: Error>(name: &str) -> Result<OpAddressMode, E> {
+    use hassel_lib6502::OpAddressMode::*;
+    Ok(match name {
+        "Absolute" => Absolute,
+        "AbsoluteOffsetX" => AbsoluteOffsetX,
+        "AbsoluteOffsetY" => AbsoluteOffsetY,
+        "Immediate" => Immediate,
+        "Implied" => Implied,
+        "Indirect" => Indirect,
+        "PCOffset" => PCOffset,
+        "PostIndirectY" => PostIndirectY,
+        "PreIndirectX" => PreIndirectX,
+        "ZeroPage" => ZeroPage,
+        "ZeroPageOffsetX" => ZeroPageOffsetX,
+        "ZeroPageOffsetY" => ZeroPageOffsetY,
+        _ => return Err(E::custom(format!("unknown address mode \"{}\"", name))),
+    })
+}
+
+pub mod address_mode {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(mode: &OpAddressMode, serializer: S) -> Result<S::Ok, S::Error> {
+        mode_name(*mode).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OpAddressMode, D::Error> {
+        mode_from_name(&String::deserialize(deserializer)?)
+    }
+}
+
+/// Op codes are written as their mnemonic and address mode
+pub mod op_code {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Op {
+        op: String,
+        mode: String,
+    }
+
+    pub fn serialize<S: Serializer>(code: &&'static OpCode, serializer: S) -> Result<S::Ok, S::Error> {
+        Op {
+            op: format!("{:?}", code.class).to_uppercase(),
+            mode: mode_name(code.address_mode),
+        }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<&'static OpCode, D::Error> {
+        let op = Op::deserialize(deserializer)?;
+        let class = OpClass::from_name(&op.op).ok_or_else(|| D::Error::custom(format!("unknown op \"{}\"", op.op)))?;
+        let mode = mode_from_name(&op.mode)?;
+        OpCode::find_by_class_and_mode(class, mode)
+            .ok_or_else(|| D::Error::custom(format!("{} has no {} address mode", op.op, op.mode)))
+    }
+}
+
+pub mod param {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    enum Param {
+        None,
+        Byte(u8),
+        Word(u16),
+    }
+
+    pub fn serialize<S: Serializer>(param: &OpParam, serializer: S) -> Result<S::Ok, S::Error> {
+        match *param {
+            OpParam::None => Param::None,
+            OpParam::Byte(value) => Param::Byte(value),
+            OpParam::Word(value) => Param::Word(value),
+        }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OpParam, D::Error> {
+        Ok(match Param::deserialize(deserializer)? {
+            Param::None => OpParam::None,
+            Param::Byte(value) => OpParam::Byte(value),
+            Param::Word(value) => OpParam::Word(value),
+        })
+    }
+}
@@ -0,0 +1,173 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ast;
+use ir::{lookup_candidates, IRBlock, IRChunk, IRFillKind, IRParam, IRRemovable, IR};
+use src_tag::SrcTag;
+
+/// Removable code that nothing kept refers to
+#[derive(Debug)]
+pub struct RemovedCode {
+    pub removable: IRRemovable,
+    /// Where the first removed block starts
+    pub tag: SrcTag,
+    /// Size in bytes, not counting padding and alignment
+    pub length: usize,
+}
+
+/// Removes every removable proc and segment that can't be reached by following references from the code that's
+/// always kept, which includes the vectors, and from the given exported entry points. Exports of removed code are
+/// kept, so that imports of them that nothing uses still link.
+pub fn remove_unreachable(ir: &mut IR, entry_points: &[Arc<String>]) -> Vec<RemovedCode> {
+    let mut removables: Vec<&IRRemovable> = Vec::new();
+    let nodes: Vec<Option<usize>> = ir.blocks
+        .iter()
+        .map(|block| {
+            block.removable.as_ref().map(|removable| {
+                match removables.iter().position(|known| *known == removable) {
+                    Some(index) => index,
+                    None => {
+                        removables.push(removable);
+                        removables.len() - 1
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // Where each label and constant is defined, and which export each import refers to
+    let mut owners: HashMap<&Arc<String>, Option<usize>> = HashMap::new();
+    for (block, &node) in ir.blocks.iter().zip(&nodes) {
+        if let Some(ref label) = block.label {
+            owners.insert(label, node);
+        }
+        for chunk in &block.chunks {
+            if let IRChunk::Constant(ref constant) = *chunk {
+                owners.insert(&constant.name, node);
+            }
+        }
+    }
+    let exports: HashMap<&Arc<String>, &Arc<String>> =
+        ir.exports.iter().map(|export| (&export.name, &export.local)).collect();
+    let imports: HashMap<&Arc<String>, &Arc<String>> =
+        ir.imports.iter().map(|import| (&import.local, &import.name)).collect();
+    let owner = |scope: &str, name: &str| -> Option<Option<usize>> {
+        for candidate in lookup_candidates(scope, name) {
+            if let Some(&node) = owners.get(&candidate) {
+                return Some(node);
+            }
+            if let Some(export) = imports.get(&candidate).and_then(|name| exports.get(name)) {
+                return owners.get(export).cloned();
+            }
+        }
+        None
+    };
+
+    let mut references: Vec<Vec<usize>> = vec![Vec::new(); removables.len()];
+    let mut reached = vec![false; removables.len()];
+    let mut pending = Vec::new();
+    for (block, &node) in ir.blocks.iter().zip(&nodes) {
+        for name in referenced_names(block) {
+            if let Some(Some(target)) = owner(&block.scope, name) {
+                match node {
+                    Some(node) => references[node].push(target),
+                    None => pending.push(target),
+                }
+            }
+        }
+    }
+    for entry_point in entry_points {
+        let local = exports.get(entry_point).map(|local| format!("::{}", local));
+        if let Some(Some(target)) = local.and_then(|local| owner("", &local)) {
+            pending.push(target);
+        }
+    }
+    while let Some(node) = pending.pop() {
+        if !reached[node] {
+            reached[node] = true;
+            pending.extend(references[node].iter().cloned());
+        }
+    }
+
+    let mut removed: Vec<RemovedCode> = Vec::new();
+    for (block, &node) in ir.blocks.iter().zip(&nodes) {
+        let node = match node {
+            Some(node) if !reached[node] => node,
+            _ => continue,
+        };
+        let length: usize = block.chunks.iter().map(chunk_length).sum();
+        match removed.iter().position(|code| code.removable == *removables[node]) {
+            Some(index) => removed[index].length += length,
+            None => removed.push(RemovedCode {
+                removable: removables[node].clone(),
+                tag: block.tag,
+                length: length,
+            }),
+        }
+    }
+
+    let mut kept = nodes.into_iter().map(|node| node.map(|node| reached[node]).unwrap_or(true));
+    ir.blocks.retain(|_| kept.next().unwrap());
+    removed
+}
+
+/// Size of a chunk before its block is placed, when padding and alignment aren't known yet
+fn chunk_length(chunk: &IRChunk) -> usize {
+    match *chunk {
+        IRChunk::Fill(ref fill) => match fill.kind {
+            IRFillKind::Repeat(count) | IRFillKind::Reserve(count) => count as usize,
+            IRFillKind::PadTo(_) | IRFillKind::Align(_) => 0,
+        },
+        ref chunk => chunk.len(),
+    }
+}
+
+fn referenced_names(block: &IRBlock) -> Vec<&Arc<String>> {
+    let mut names = Vec::new();
+    for chunk in &block.chunks {
+        match *chunk {
+            IRChunk::Op(ref op) => match op.param {
+                IRParam::Unresolved(_, ref expr) | IRParam::UnresolvedByte(_, ref expr) => expr_names(expr, &mut names),
+                IRParam::Resolved(..) => {}
+            },
+            IRChunk::Data(ref data) => for expr in &data.exprs {
+                expr_names(expr, &mut names);
+            },
+            IRChunk::Vector(_, ref label, _) => names.push(label),
+            IRChunk::Assert(ref assert) => expr_names(&assert.expr, &mut names),
+            IRChunk::RunAddress(ref run_address) => expr_names(&run_address.expr, &mut names),
+            IRChunk::Constant(ref constant) => expr_names(&constant.expr, &mut names),
+            IRChunk::Print(ref print) => for item in &print.items {
+                if let ast::PrintItem::Expr(ref expr) = *item {
+                    expr_names(expr, &mut names);
+                }
+            },
+            IRChunk::PointerCheck(_) | IRChunk::Fill(_) => {}
+        }
+    }
+    names
+}
+
+fn expr_names<'a>(expr: &'a ast::Expr, names: &mut Vec<&'a Arc<String>>) {
+    match *expr {
+        ast::Expr::Name(_, ref name) | ast::Expr::SizeOf(_, ref name) => names.push(name),
+        ast::Expr::Unary(_, _, ref operand) => expr_names(operand, names),
+        ast::Expr::Binary(_, _, ref left, ref right) => {
+            expr_names(left, names);
+            expr_names(right, names);
+        }
+        ast::Expr::Call(_, _, ref arguments) => for argument in arguments {
+            expr_names(argument, names);
+        },
+        ast::Expr::Number(..) | ast::Expr::ProgramCounter(_) | ast::Expr::Defined(..) => {}
+    }
+}
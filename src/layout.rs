@@ -0,0 +1,115 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use serde_json;
+
+use error;
+
+/// Segment that code goes into until the first `.segment`
+pub const DEFAULT_SEGMENT: &str = "CODE";
+
+/// A range of memory that segments are placed into
+#[derive(Debug, Clone, Deserialize, new)]
+pub struct MemoryArea {
+    pub name: String,
+    pub start: u16,
+    /// Size in bytes, which can be up to $10000
+    pub size: u32,
+    /// Byte that the unused part of the area is filled with in the output
+    #[serde(default)]
+    pub fill: u8,
+    /// Whether the area is written to the output; RAM areas only reserve addresses
+    pub output: bool,
+}
+
+/// Assigns a segment to the area that it's placed in
+#[derive(Debug, Clone, Deserialize, new)]
+pub struct SegmentPlacement {
+    pub name: String,
+    pub area: String,
+}
+
+/// Maps segments to memory areas. Segments in the same area are placed one after another in the order that
+/// they're listed, and the output is every output area in order, each filled out to its full size.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MemoryLayout {
+    pub areas: Vec<MemoryArea>,
+    pub segments: Vec<SegmentPlacement>,
+}
+
+impl MemoryLayout {
+    pub fn new() -> MemoryLayout {
+        MemoryLayout::default()
+    }
+
+    /// Reads a layout from JSON, such as
+    /// `{"areas": [{"name": "ROM", "start": 32768, "size": 32768, "fill": 255, "output": true}],
+    /// "segments": [{"name": "CODE", "area": "ROM"}]}`
+    pub fn from_json(json: &str) -> error::Result<MemoryLayout> {
+        let layout: MemoryLayout = serde_json::from_str(json)?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    pub fn add_area(&mut self, area: MemoryArea) {
+        self.areas.push(area);
+    }
+
+    pub fn add_segment<N: Into<String>, A: Into<String>>(&mut self, name: N, area: A) {
+        self.segments.push(SegmentPlacement::new(name.into(), area.into()));
+    }
+
+    /// Checks that every segment is placed in an area that exists, and that every area fits in memory
+    pub fn validate(&self) -> error::Result<()> {
+        for (index, area) in self.areas.iter().enumerate() {
+            if area.start as u32 + area.size > 0x10000 {
+                bail!(error::ErrorKind::InvalidLayout(format!(
+                    "area \"{}\" extends past $FFFF",
+                    area.name
+                )));
+            }
+            if self.areas[..index].iter().any(|other| other.name == area.name) {
+                bail!(error::ErrorKind::InvalidLayout(format!(
+                    "area \"{}\" is defined more than once",
+                    area.name
+                )));
+            }
+        }
+        for (index, segment) in self.segments.iter().enumerate() {
+            if self.area_index(&segment.area).is_none() {
+                bail!(error::ErrorKind::InvalidLayout(format!(
+                    "segment \"{}\" is placed in unknown area \"{}\"",
+                    segment.name, segment.area
+                )));
+            }
+            if self.segments[..index].iter().any(|other| other.name == segment.name) {
+                bail!(error::ErrorKind::InvalidLayout(format!(
+                    "segment \"{}\" is placed more than once",
+                    segment.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns where the segment comes in the placement order
+    pub fn segment_index(&self, name: &str) -> Option<usize> {
+        self.segments.iter().position(|segment| segment.name == name)
+    }
+
+    pub fn area_index(&self, name: &str) -> Option<usize> {
+        self.areas.iter().position(|area| area.name == name)
+    }
+
+    /// Returns the index of the area that the segment is placed in
+    pub fn segment_area(&self, name: &str) -> Option<usize> {
+        self.segment_index(name)
+            .and_then(|index| self.area_index(&self.segments[index].area))
+    }
+}
@@ -10,6 +10,7 @@
 #![recursion_limit = "1024"]
 
 extern crate lalrpop_util;
+extern crate regex;
 
 #[macro_use]
 extern crate derive_new;
@@ -25,11 +26,19 @@ extern crate serde_derive;
 
 extern crate hassel_lib6502;
 
+pub mod archive;
 mod assembler;
 pub mod ast;
+pub mod diagnostic;
 pub mod error;
+pub mod format;
 pub mod ir;
+pub mod layout;
+mod linker;
+pub mod object;
 pub mod src_tag;
-mod src_unit;
+pub mod src_unit;
+pub mod target;
 
 pub use assembler::{Assembler, AssemblerOutput};
+pub use linker::Linker;
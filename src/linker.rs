@@ -0,0 +1,332 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use archive::Archive;
+use assembler::{Assembler, AssemblerOutput};
+use ast;
+use error::{self, Warning, WarningConfig};
+use format::OutputFormat;
+use format::ines::InesOptions;
+use ir::{qualify, unlinked_name, IRBlock, IRChunk, IRExport, IRImport, IRParam, IRRemovable, IR, MODULE_PREFIX};
+use ir::gen::IRGenerator;
+use ir::prune::{self, RemovedCode};
+use layout::MemoryLayout;
+use object::ObjectFile;
+use src_tag::SrcTag;
+use src_unit::SrcUnits;
+use target::{Cpu, Target};
+
+/// Places the blocks of several object files and resolves the references between them. Each object's names are
+/// kept in a module of their own, and only exported names can be imported by other objects.
+#[derive(Default)]
+pub struct Linker {
+    src_units: SrcUnits,
+    blocks: Vec<IRBlock>,
+    exports: Vec<IRExport>,
+    imports: Vec<IRImport>,
+    modules: HashSet<String>,
+    entry_points: Vec<Arc<String>>,
+    /// Archive members that haven't been pulled into the link yet
+    library: Vec<Option<ObjectFile>>,
+    /// Library member that exports each name
+    library_index: HashMap<String, usize>,
+    /// iNES header fields given by the objects, where later objects take the place of earlier ones
+    ines: InesOptions,
+    target: Target,
+    warnings: WarningConfig,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    /// Sets how many columns a tab advances to in reported locations
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.src_units.set_tab_width(tab_width);
+    }
+
+    pub fn set_cpu(&mut self, cpu: Cpu) {
+        self.target.cpu = cpu;
+    }
+
+    /// Sets the byte used for gaps between blocks in the output
+    pub fn set_fill_byte(&mut self, fill_byte: u8) {
+        self.target.fill_byte = fill_byte;
+    }
+
+    /// Places code by segment into the layout's memory areas rather than by `.org`
+    pub fn set_layout(&mut self, layout: MemoryLayout) -> error::Result<()> {
+        layout.validate()?;
+        self.target.layout = Some(layout);
+        Ok(())
+    }
+
+    /// Sets the file format that the output's bytes are written in
+    pub fn set_output_format(&mut self, format: OutputFormat) {
+        self.target.format = format;
+    }
+
+    /// Sets iNES header fields, which take the place of those that the program gives
+    pub fn set_ines_options(&mut self, options: InesOptions) {
+        self.target.ines = options;
+    }
+
+    pub fn set_warning(&mut self, warning: Warning, enabled: bool) {
+        self.warnings.set_enabled(warning, enabled);
+    }
+
+    pub fn set_warnings_as_errors(&mut self, warnings_as_errors: bool) {
+        self.warnings.set_warnings_as_errors(warnings_as_errors);
+    }
+
+    /// Keeps the removable code that an exported name is in, along with everything that it refers to. Code that
+    /// isn't removable, such as the vectors, is always kept.
+    pub fn add_entry_point<N: Into<String>>(&mut self, name: N) {
+        self.entry_points.push(Arc::new(name.into()));
+    }
+
+    /// Adds an object's blocks after those of the objects added before it
+    pub fn add_object(&mut self, object: ObjectFile) {
+        let relocator = Relocator {
+            unit_offset: self.src_units.units().len(),
+            module: self.module_name(&object),
+        };
+        self.ines.merge(&object.ir.ines);
+        for unit in object.units {
+            self.src_units.push_unit(unit.name, unit.source);
+        }
+        for mut block in object.ir.blocks {
+            relocator.block(&mut block);
+            self.blocks.push(block);
+        }
+        for mut export in object.ir.exports {
+            relocator.tag(&mut export.tag);
+            export.local = qualify(&relocator.module, &export.local);
+            self.exports.push(export);
+        }
+        for mut import in object.ir.imports {
+            relocator.tag(&mut import.tag);
+            import.local = qualify(&relocator.module, &import.local);
+            self.imports.push(import);
+        }
+    }
+
+    /// Makes an archive's members available to the link. A member is only added once something imports a name
+    /// that it exports. When several archives export a name, the first one added is used.
+    pub fn add_archive(&mut self, archive: Archive) {
+        let offset = self.library.len();
+        for (name, &index) in archive.index() {
+            self.library_index.entry(name.clone()).or_insert(offset + index);
+        }
+        self.library
+            .extend(archive.into_members().into_iter().map(|member| Some(member.object)));
+    }
+
+    /// Adds the library members that export names which are imported but not yet exported, until no more
+    /// are needed
+    fn pull_library_members(&mut self) {
+        loop {
+            let mut needed: Vec<usize> = {
+                let exported: HashSet<&Arc<String>> = self.exports.iter().map(|export| &export.name).collect();
+                self.imports
+                    .iter()
+                    .map(|import| &import.name)
+                    .chain(self.entry_points.iter())
+                    .filter(|name| !exported.contains(name))
+                    .filter_map(|name| self.library_index.get(&**name).cloned())
+                    .filter(|&index| self.library[index].is_some())
+                    .collect()
+            };
+            if needed.is_empty() {
+                return;
+            }
+            needed.sort();
+            needed.dedup();
+            for index in needed {
+                let object = self.library[index].take().unwrap();
+                self.add_object(object);
+            }
+        }
+    }
+
+    /// Names the module after the object's main source file, numbering objects that share a file name
+    fn module_name(&mut self, object: &ObjectFile) -> String {
+        let file_name = object
+            .units
+            .first()
+            .map(|unit| unit.name.replace("::", ":"))
+            .unwrap_or_default();
+        let mut module = format!("{}{}", MODULE_PREFIX, file_name);
+        let mut number = 1;
+        while self.modules.contains(&module) {
+            number += 1;
+            module = format!("{}{}#{}", MODULE_PREFIX, file_name, number);
+        }
+        self.modules.insert(module.clone());
+        module
+    }
+
+    pub fn link(mut self) -> error::Result<AssemblerOutput> {
+        self.pull_library_members();
+        for entry_point in &self.entry_points {
+            if !self.exports.iter().any(|export| export.name == *entry_point) {
+                bail!(error::ErrorKind::UnknownEntryPoint((**entry_point).clone()));
+            }
+        }
+        let mut ir = IR::new(self.blocks, self.exports, self.imports, BTreeMap::new());
+        ir.ines = self.ines;
+        let removed = prune::remove_unreachable(&mut ir, &self.entry_points);
+        let report = Linker::removal_report(&removed, &self.src_units);
+        match IRGenerator::link(ir, &self.target, &self.warnings) {
+            Ok((ir, diagnostics)) => {
+                let mut output =
+                    Assembler::build_output(ir, diagnostics, self.src_units, &self.target, &self.warnings)?;
+                output.removed = Some(report);
+                Ok(output)
+            }
+            Err(diagnostics) => Err(error::ErrorKind::Diagnostics(self.src_units, diagnostics).into()),
+        }
+    }
+
+    /// Lists the removed code with where it starts and its size, followed by the total
+    fn removal_report(removed: &[RemovedCode], src_units: &SrcUnits) -> String {
+        let mut report = String::new();
+        for code in removed {
+            let what = match code.removable {
+                IRRemovable::Proc(ref name) => format!("proc {}", unlinked_name(name)),
+                IRRemovable::Segment(ref name) => format!("segment \"{}\"", unlinked_name(name)),
+            };
+            let (row, _) = src_units.row_col(code.tag);
+            report.push_str(&format!(
+                "{} at {}:{}, {} byte(s)\n",
+                what,
+                src_units.name(code.tag.unit),
+                row,
+                code.length
+            ));
+        }
+        let total: usize = removed.iter().map(|code| code.length).sum();
+        report.push_str(&format!("; removed {} byte(s)\n", total));
+        report
+    }
+}
+
+/// Points an object's tags at its units in the linker's source units, and moves its names into its module
+struct Relocator {
+    unit_offset: usize,
+    module: String,
+}
+
+impl Relocator {
+    fn tag(&self, tag: &mut SrcTag) {
+        if *tag != SrcTag::invalid() {
+            tag.unit += self.unit_offset;
+        }
+    }
+
+    /// Names that start with `::` refer to the object's global scope, which is now its module. Other references
+    /// are looked up from the block's scope, which is already in the module.
+    fn reference(&self, name: &mut Arc<String>) {
+        if name.starts_with("::") {
+            *name = Arc::new(format!("::{}::{}", self.module, &name[2..]));
+        }
+    }
+
+    fn block(&self, block: &mut IRBlock) {
+        self.tag(&mut block.tag);
+        block.scope = qualify(&self.module, &block.scope);
+        block.removable = match block.removable.take() {
+            Some(IRRemovable::Proc(name)) => Some(IRRemovable::Proc(qualify(&self.module, &name))),
+            Some(IRRemovable::Segment(name)) => Some(IRRemovable::Segment(qualify(&self.module, &name))),
+            None => None,
+        };
+        if let Some(ref mut label) = block.label {
+            *label = qualify(&self.module, label);
+        }
+        for chunk in &mut block.chunks {
+            self.chunk(chunk);
+        }
+    }
+
+    fn chunk(&self, chunk: &mut IRChunk) {
+        match *chunk {
+            IRChunk::Op(ref mut op) => {
+                self.tag(&mut op.tag);
+                match op.param {
+                    IRParam::Unresolved(_, ref mut expr) | IRParam::UnresolvedByte(_, ref mut expr) => self.expr(expr),
+                    IRParam::Resolved(..) => {}
+                }
+            }
+            IRChunk::Data(ref mut data) => {
+                self.tag(&mut data.tag);
+                for expr in &mut data.exprs {
+                    self.expr(expr);
+                }
+            }
+            IRChunk::Vector(ref mut tag, ref mut label, _) => {
+                self.tag(tag);
+                self.reference(label);
+            }
+            IRChunk::PointerCheck(ref mut tag) => self.tag(tag),
+            IRChunk::Fill(ref mut fill) => self.tag(&mut fill.tag),
+            IRChunk::Assert(ref mut assert) => {
+                self.tag(&mut assert.tag);
+                self.expr(&mut assert.expr);
+            }
+            IRChunk::Constant(ref mut constant) => {
+                self.tag(&mut constant.tag);
+                constant.name = qualify(&self.module, &constant.name);
+                self.expr(&mut constant.expr);
+            }
+            IRChunk::RunAddress(ref mut run_address) => {
+                self.tag(&mut run_address.tag);
+                self.expr(&mut run_address.expr);
+            }
+            IRChunk::Print(ref mut print) => {
+                self.tag(&mut print.tag);
+                for item in &mut print.items {
+                    if let ast::PrintItem::Expr(ref mut expr) = *item {
+                        self.expr(expr);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expr(&self, expr: &mut ast::Expr) {
+        match *expr {
+            ast::Expr::Number(ref mut tag, _) | ast::Expr::ProgramCounter(ref mut tag) => self.tag(tag),
+            ast::Expr::Name(ref mut tag, ref mut name)
+            | ast::Expr::SizeOf(ref mut tag, ref mut name)
+            | ast::Expr::Defined(ref mut tag, ref mut name) => {
+                self.tag(tag);
+                self.reference(name);
+            }
+            ast::Expr::Unary(ref mut tag, _, ref mut operand) => {
+                self.tag(tag);
+                self.expr(operand);
+            }
+            ast::Expr::Binary(ref mut tag, _, ref mut left, ref mut right) => {
+                self.tag(tag);
+                self.expr(left);
+                self.expr(right);
+            }
+            ast::Expr::Call(ref mut tag, _, ref mut args) => {
+                self.tag(tag);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use serde_json;
+use std::sync::Arc;
+
+use diagnostic::Diagnostic;
+use error;
+use ir::IR;
+use src_unit::SrcUnits;
+
+/// Written in every object file so that other JSON isn't mistaken for one
+pub const OBJECT_FORMAT: &str = "hassel_asm object";
+/// Bumped whenever the IR changes in a way that older object files can't be read as
+pub const OBJECT_VERSION: u32 = 4;
+
+/// Source file that went into an object file, kept so that link errors can point at it
+#[derive(Debug, Clone, Serialize, Deserialize, new)]
+pub struct ObjectUnit {
+    pub name: String,
+    pub source: String,
+}
+
+/// Assembled but unplaced code. Blocks have no addresses yet, and their references to labels and constants are
+/// kept as unresolved params, which the linker evaluates once it has placed the blocks of every object.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ObjectFile {
+    format: String,
+    version: u32,
+    /// Source units that the IR's tags refer to, in order of their unit ids
+    pub units: Vec<ObjectUnit>,
+    pub ir: IR,
+    /// Warnings reported while compiling the object
+    #[serde(skip)]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ObjectFile {
+    pub fn new(src_units: &SrcUnits, ir: IR, diagnostics: Vec<Diagnostic>) -> ObjectFile {
+        ObjectFile {
+            format: OBJECT_FORMAT.into(),
+            version: OBJECT_VERSION,
+            units: src_units
+                .units()
+                .iter()
+                .map(|unit| ObjectUnit::new(unit.name.clone(), unit.source.clone()))
+                .collect(),
+            ir: ir,
+            diagnostics: diagnostics,
+        }
+    }
+
+    pub fn from_json(json: &str) -> error::Result<ObjectFile> {
+        let object: ObjectFile = serde_json::from_str(json)?;
+        object.check_version()?;
+        Ok(object)
+    }
+
+    /// Checks that the object was written by a compatible version of the assembler
+    pub(crate) fn check_version(&self) -> error::Result<()> {
+        if self.format != OBJECT_FORMAT {
+            bail!(error::ErrorKind::InvalidObject(format!(
+                "expected format \"{}\", but found \"{}\"",
+                OBJECT_FORMAT, self.format
+            )));
+        }
+        if self.version != OBJECT_VERSION {
+            bail!(error::ErrorKind::InvalidObject(format!(
+                "expected version {}, but found version {}",
+                OBJECT_VERSION, self.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the names that the object exports
+    pub fn exports(&self) -> Vec<&Arc<String>> {
+        self.ir.exports.iter().map(|export| &export.name).collect()
+    }
+
+    pub fn to_json(&self) -> error::Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Returns the source units, for rendering the object's diagnostics
+    pub fn src_units(&self) -> SrcUnits {
+        let mut src_units = SrcUnits::new();
+        for unit in &self.units {
+            src_units.push_unit(unit.name.clone(), unit.source.clone());
+        }
+        src_units
+    }
+}
@@ -8,7 +8,7 @@
 //
 
 /// Represents the character offset in the program code where something is located
-#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 pub struct SrcTag {
     pub unit: usize,
     pub offset: usize,
@@ -29,13 +29,14 @@ impl SrcTag {
         }
     }
 
-    /// Returns the (row, column) of this tag in the given program text
+    /// Returns the (row, column) of this tag in the given program text. This scans the text from the start,
+    /// so prefer `SrcUnits::row_col`, which uses a precomputed line index.
     pub fn row_col(&self, program: &str) -> (usize, usize) {
         let mut row: usize = 1;
         let mut col: usize = 1;
 
-        for i in 0..self.offset {
-            if &program[i..i + 1] == "\n" {
+        for chr in program[..self.offset].chars() {
+            if chr == '\n' {
                 row += 1;
                 col = 1;
             } else {
@@ -9,16 +9,82 @@
 
 use src_tag::SrcTag;
 
-#[derive(Debug, Serialize, new)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SrcUnit {
     pub id: usize,
     pub name: String,
     pub source: String,
+    /// Byte offset of the start of each line, so that rows can be found by binary search
+    #[serde(skip)]
+    line_starts: Vec<usize>,
 }
 
-#[derive(Debug, Default, Serialize)]
+impl SrcUnit {
+    pub fn new(id: usize, name: String, source: String) -> SrcUnit {
+        let mut line_starts = vec![0];
+        line_starts.extend(
+            source
+                .bytes()
+                .enumerate()
+                .filter(|&(_, byte)| byte == b'\n')
+                .map(|(index, _)| index + 1),
+        );
+        SrcUnit {
+            id: id,
+            name: name,
+            source: source,
+            line_starts: line_starts,
+        }
+    }
+
+    /// Returns the 1-based row that contains the given byte offset
+    pub fn row(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        }
+    }
+
+    /// Returns the byte offset where the given 1-based row starts
+    pub fn line_start(&self, row: usize) -> usize {
+        self.line_starts[row - 1]
+    }
+
+    /// Returns the 1-based (row, column) of the given byte offset. Columns are counted in characters,
+    /// with tabs advancing to the next multiple of `tab_width`.
+    pub fn row_col(&self, offset: usize, tab_width: usize) -> (usize, usize) {
+        let row = self.row(offset);
+        let mut end = offset.min(self.source.len());
+        while !self.source.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let mut col = 1;
+        for chr in self.source[self.line_start(row)..end].chars() {
+            if chr == '\t' && tab_width > 1 {
+                col += tab_width - (col - 1) % tab_width;
+            } else {
+                col += 1;
+            }
+        }
+        (row, col)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SrcUnits {
     units: Vec<SrcUnit>,
+    #[serde(skip)]
+    tab_width: usize,
+}
+
+impl Default for SrcUnits {
+    fn default() -> SrcUnits {
+        SrcUnits {
+            units: Vec::new(),
+            tab_width: 1,
+        }
+    }
 }
 
 impl SrcUnits {
@@ -26,6 +92,11 @@ impl SrcUnits {
         Default::default()
     }
 
+    /// Sets how many columns a tab advances to when reporting locations; defaults to 1
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width;
+    }
+
     pub fn name(&self, unit_id: usize) -> &String {
         &self.units[unit_id].name
     }
@@ -38,6 +109,10 @@ impl SrcUnits {
         &self.units[unit_id]
     }
 
+    pub fn units(&self) -> &[SrcUnit] {
+        &self.units
+    }
+
     pub fn unit_by_name(&self, name: &str) -> Option<&SrcUnit> {
         for unit in &self.units {
             if unit.name == name {
@@ -47,19 +122,40 @@ impl SrcUnits {
         None
     }
 
+    /// Returns the 1-based (row, column) that the tag points to
+    pub fn row_col(&self, tag: SrcTag) -> (usize, usize) {
+        self.units[tag.unit].row_col(tag.offset, self.tab_width)
+    }
+
     pub fn line_comment(&self, tag: SrcTag) -> String {
-        let (row, col) = tag.row_col(&self.units[tag.unit].source);
+        let (row, col) = self.row_col(tag);
         let line = tag.line(&self.units[tag.unit].source);
         format!("{}:{}:{}: {}", self.units[tag.unit].name, row, col, line)
     }
 
     pub fn push_unit(&mut self, name: String, source: String) -> usize {
         let unit_id = self.units.len();
-        self.units.push(SrcUnit {
-            id: unit_id,
-            name: name,
-            source: source,
-        });
+        self.units.push(SrcUnit::new(unit_id, name, source));
         unit_id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_col() {
+        let unit = SrcUnit::new(0, "test.s".into(), "; caf\u{e9} \u{2713}\nLDA #1\n\tJMP foo".into());
+        let jmp = unit.source.find("JMP").unwrap();
+        let foo = unit.source.find("foo").unwrap();
+
+        assert_eq!((1, 1), unit.row_col(0, 1));
+        assert_eq!((1, 8), unit.row_col(unit.source.find('\u{2713}').unwrap(), 1));
+        assert_eq!((2, 1), unit.row_col(unit.source.find("LDA").unwrap(), 1));
+        assert_eq!((3, 2), unit.row_col(jmp, 1));
+        assert_eq!((3, 5), unit.row_col(jmp, 4));
+        assert_eq!((3, 9), unit.row_col(foo, 4));
+        assert_eq!((3, 12), unit.row_col(unit.source.len(), 4));
+    }
+}
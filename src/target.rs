@@ -0,0 +1,63 @@
+//
+// Copyright 2017 hassel_asm Developers
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//
+
+use format::OutputFormat;
+use format::ines::InesOptions;
+use layout::MemoryLayout;
+
+/// The processor variant that the assembled program will run on
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum Cpu {
+    /// Original NMOS 6502, which has the `JMP ($xxFF)` page wrap bug
+    Nmos6502,
+    /// CMOS 65C02, which fixed the `JMP ($xxFF)` page wrap bug
+    Cmos65C02,
+}
+
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::Nmos6502
+    }
+}
+
+impl Cpu {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Cpu::Nmos6502 => "6502",
+            Cpu::Cmos65C02 => "65c02",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Cpu> {
+        match name {
+            "6502" => Some(Cpu::Nmos6502),
+            "65c02" | "65C02" => Some(Cpu::Cmos65C02),
+            _ => None,
+        }
+    }
+
+    /// True if `JMP (ptr)` with `ptr` at `$xxFF` reads its high byte from `$xx00`
+    pub fn has_indirect_jmp_bug(&self) -> bool {
+        *self == Cpu::Nmos6502
+    }
+}
+
+/// Describes the machine and the image that the program is assembled for
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    pub cpu: Cpu,
+    /// Byte used for gaps in the output and for padding that doesn't name its own fill value
+    pub fill_byte: u8,
+    /// Where segments are placed; without one, code is placed with `.org`
+    pub layout: Option<MemoryLayout>,
+    /// File format that the output is written in
+    pub format: OutputFormat,
+    /// iNES header settings, which override those given in the program
+    pub ines: InesOptions,
+}
@@ -9,7 +9,17 @@
 
 extern crate hassel_asm;
 
-use hassel_asm::Assembler;
+use hassel_asm::{error, Assembler, Linker};
+use hassel_asm::archive::Archive;
+use hassel_asm::diagnostic::{DiagnosticCode, Span};
+use hassel_asm::error::Warning;
+use hassel_asm::format::{self, ImageSegment, OutputFormat};
+use hassel_asm::format::ines::InesOptions;
+use hassel_asm::format::o65::{O65Export, O65File, O65Relocation, O65Segment};
+use hassel_asm::ir::IRRelocationKind;
+use hassel_asm::layout::{MemoryArea, MemoryLayout};
+use hassel_asm::object::ObjectFile;
+use hassel_asm::target::Cpu;
 
 #[test]
 fn org_and_pad_test() {
@@ -20,4 +30,908 @@ fn org_and_pad_test() {
 
     let expected_bytes = include_bytes!("./org_and_pad.rom").to_vec();
     assert_eq!(expected_bytes, result.bytes.unwrap());
-}
\ No newline at end of file
+}
+fn parse_notes(program: &str) -> Vec<String> {
+    let mut assembler = Assembler::new();
+    match *assembler.parse_unit("test.s", program).unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            diagnostics.iter().flat_map(|d| d.notes.iter().cloned()).collect()
+        }
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+#[test]
+fn parse_error_notes_test() {
+    assert_eq!("expected name", parse_notes(".import helper, zpvar: zp\n")[0]);
+    let note = parse_notes("LDA (,\n")[0].clone();
+    assert!(note.ends_with("`~`, name or number"), "{}", note);
+    let note = parse_notes("1\n")[0].clone();
+    assert!(note.ends_with("name, comment, instruction or label"), "{}", note);
+}
+
+#[test]
+fn collects_all_diagnostics_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit("test.s", "FOO\nLDA #$1FFFF\nBAR\n")
+        .unwrap();
+    let err = assembler.assemble().unwrap_err();
+
+    match *err.kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            let codes: Vec<DiagnosticCode> = diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(
+                vec![
+                    DiagnosticCode::UnknownOpcode,
+                    DiagnosticCode::NumberOutOfRange,
+                    DiagnosticCode::UnknownOpcode,
+                ],
+                codes
+            );
+            assert_eq!(Some(Span::new(0, 9, 9)), diagnostics[1].primary);
+        }
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+#[test]
+fn warnings_test() {
+    let program = "start:\nunused:\n    LDA $10\n    JMP start\n";
+
+    let mut assembler = Assembler::new();
+    assembler.parse_unit("test.s", program).unwrap();
+    assert!(assembler.assemble().unwrap().diagnostics.is_empty());
+
+    let mut assembler = Assembler::new();
+    assembler.set_warning(Warning::UnusedLabel, true);
+    assembler.set_warning(Warning::ImplicitZeroPage, true);
+    assembler.parse_unit("test.s", program).unwrap();
+    let codes: Vec<DiagnosticCode> = assembler
+        .assemble()
+        .unwrap()
+        .diagnostics
+        .iter()
+        .map(|d| d.code)
+        .collect();
+    assert_eq!(
+        vec![
+            DiagnosticCode::Warning(Warning::ImplicitZeroPage),
+            DiagnosticCode::Warning(Warning::UnusedLabel),
+        ],
+        codes
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.set_warning(Warning::UnusedLabel, true);
+    assembler.set_warnings_as_errors(true);
+    assembler.parse_unit("test.s", program).unwrap();
+    assert!(assembler.assemble().is_err());
+}
+
+#[test]
+fn jmp_indirect_page_wrap_test() {
+    let program = ".org $02FF\nvec:\n.vector start\nstart:\n    JMP (vec)\n";
+
+    let mut assembler = Assembler::new();
+    assembler.parse_unit("test.s", program).unwrap();
+    let err = assembler.assemble().unwrap_err();
+    match *err.kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            assert_eq!(DiagnosticCode::JmpIndirectPageWrap, diagnostics[0].code);
+        }
+        _ => panic!("expected diagnostics"),
+    }
+
+    let mut assembler = Assembler::new();
+    assembler.set_cpu(Cpu::Cmos65C02);
+    assembler.parse_unit("test.s", program).unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        DiagnosticCode::Warning(Warning::JmpIndirectPageWrap),
+        output.diagnostics[0].code
+    );
+
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit("test.s", ".org $02FF\nvec:\n.indirect vec\n")
+        .unwrap();
+    assert!(assembler.assemble().is_err());
+}
+
+fn diagnostic_codes(program: &str) -> Vec<DiagnosticCode> {
+    let mut assembler = Assembler::new();
+    assembler.parse_unit("test.s", program).unwrap();
+    match assembler.assemble() {
+        Ok(output) => output.diagnostics.iter().map(|d| d.code).collect(),
+        Err(err) => match *err.kind() {
+            error::ErrorKind::Diagnostics(_, ref diagnostics) => diagnostics.iter().map(|d| d.code).collect(),
+            _ => panic!("expected diagnostics"),
+        },
+    }
+}
+
+#[test]
+fn placement_errors_test() {
+    assert_eq!(
+        vec![DiagnosticCode::OverlappingBlocks],
+        diagnostic_codes(".org $1000\nLDA #1\n.org $1001\nNOP\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::AddressOverflow],
+        diagnostic_codes(".org $FFFE\n.word 1, 2\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::OperandSize],
+        diagnostic_codes("LDA #$1234\n")
+    );
+}
+
+#[test]
+fn pad_test() {
+    let mut assembler = Assembler::new();
+    assembler.set_fill_byte(0xFF);
+    assembler
+        .parse_unit("test.s", ".org $1000\nNOP\n.pad $1004, $EA\nNOP\n.org $1008\nNOP\n.pad $100A\nNOP\n")
+        .unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(
+        vec![0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xFF, 0xFF, 0xFF, 0xEA, 0xFF, 0xEA],
+        bytes
+    );
+
+    assert_eq!(
+        vec![DiagnosticCode::PadBackwards],
+        diagnostic_codes(".org $1000\nNOP\nNOP\n.pad $1001\n")
+    );
+}
+
+#[test]
+fn relocation_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".org $E000\nNOP\n.rorg $0200\nram:\nJMP ram\n.endrorg\nafter:\nJMP after\n",
+        )
+        .unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(vec![0xEA, 0x4C, 0x00, 0x02, 0x4C, 0x04, 0xE0], bytes);
+
+    assert_eq!(
+        vec![DiagnosticCode::OrgBelowOutput],
+        diagnostic_codes(".org $E000\nNOP\n.org $A000\nNOP\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::UnbalancedRelocation],
+        diagnostic_codes(".org $E000\n.rorg $A000\nNOP\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::UnbalancedRelocation],
+        diagnostic_codes(".org $E000\nNOP\n.endrorg\n")
+    );
+}
+
+#[test]
+fn align_res_fill_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".org $0200\nbuffer: .res 16\npointer: .res 2\n\
+             .org $E000\nLDA pointer\n.align 4\ntable: .byte 1\n.fill 2, $AA\n.res 1, $55\nNOP\n",
+        )
+        .unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(
+        vec![0xAD, 0x10, 0x02, 0x00, 0x01, 0xAA, 0xAA, 0x55, 0xEA],
+        bytes
+    );
+
+    assert_eq!(
+        vec![DiagnosticCode::NumberOutOfRange],
+        diagnostic_codes(".org $E000\n.align 0\n")
+    );
+}
+
+#[test]
+fn assert_test() {
+    assert_eq!(
+        Vec::<DiagnosticCode>::new(),
+        diagnostic_codes(
+            ".org $E000\ntable: .byte 1, 2, 3\ntable_end:\n\
+             .assert table_end - table <= 256 && (table & $FF) == 0, \"table is misplaced\"\n\
+             JMP table\n.org $FFFA\nvectors:\n.assert vectors == $FFFA\n.vector vectors\n",
+        )
+    );
+    assert_eq!(
+        vec![DiagnosticCode::AssertionFailed],
+        diagnostic_codes(".org $E001\nstart: .assert (start & $FF) == 0, \"not page aligned\"\nJMP start\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::DivisionByZero],
+        diagnostic_codes(".assert 1 / (2 - 2)\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::UserError],
+        diagnostic_codes(".error \"unsupported\"\nNOP\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::Warning(Warning::User)],
+        diagnostic_codes(".warning \"check this\"\nNOP\n")
+    );
+}
+
+#[test]
+fn program_counter_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            "zp = $10\n.org $1000\nstart:\nDEX\nBNE *-1\n.word *+2\nsize = * - start\nLDA #size\nLDA zp\n\
+             RTS\nvalue = 3\nLDA #value\n.byte .here - start\n",
+        )
+        .unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(
+        vec![
+            0xCA, 0xD0, 0xFD, 0x05, 0x10, 0xA9, 0x05, 0xA5, 0x10, 0x60, 0xA9, 0x03, 0x0C,
+        ],
+        bytes
+    );
+
+    assert_eq!(
+        vec![DiagnosticCode::DuplicateSymbol],
+        diagnostic_codes("foo = 1\nfoo = 2\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::CircularDefinition],
+        diagnostic_codes("a = b + 1\nb = a\nLDA a\n")
+    );
+}
+
+#[test]
+fn set_and_print_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".set offset = 0\nplayer_x = offset\n.set offset = offset + 1\nplayer_y = offset\n\
+             .set offset = offset + 2\n.org $1000\n.set here = *\nNOP\n.print \"size: \", offset\n\
+             .out \"here: \", here\nLDA #player_y\n.byte player_x, offset\n.word here\n",
+        )
+        .unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        vec![0xEA, 0xA9, 0x01, 0x00, 0x03, 0x00, 0x10],
+        output.bytes.unwrap()
+    );
+    let messages: Vec<&str> = output
+        .diagnostics
+        .iter()
+        .filter(|d| d.code == DiagnosticCode::Message)
+        .map(|d| d.message.as_str())
+        .collect();
+    assert_eq!(vec!["size: 3 ($3)", "here: 4096 ($1000)"], messages);
+
+    assert_eq!(
+        vec![DiagnosticCode::DuplicateSymbol],
+        diagnostic_codes("foo = 1\n.set foo = 2\n")
+    );
+    assert_eq!(
+        vec![DiagnosticCode::DuplicateSymbol],
+        diagnostic_codes(".set foo = 1\nfoo:\n")
+    );
+}
+
+#[test]
+fn scope_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".org $1000\n.proc Game\nspeed = 2\n.scope Player\nspeed = 3\nloop:\nLDA #speed\nJMP loop\n.endscope\n\
+             loop:\nLDA #speed\nLDA #Player::speed\nJMP loop\n.endproc\nJMP Game::Player::loop\nJMP Game\n",
+        )
+        .unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        vec![
+            0xA9, 0x03, 0x4C, 0x00, 0x10, 0xA9, 0x02, 0xA9, 0x03, 0x4C, 0x05, 0x10, 0x4C, 0x00, 0x10, 0x4C, 0x00,
+            0x10,
+        ],
+        output.bytes.unwrap()
+    );
+    assert_eq!(
+        "; global\nGame = $1000\n\n; Game\nloop = $1005\nspeed = $0002\n\n\
+         ; Game::Player\nloop = $1000\nspeed = $0003\n",
+        output.symbols.unwrap()
+    );
+
+    assert_eq!(vec![DiagnosticCode::UnbalancedScope], diagnostic_codes(".endscope\n"));
+    assert_eq!(vec![DiagnosticCode::UnbalancedScope], diagnostic_codes(".scope Outer\nNOP\n"));
+    assert_eq!(
+        vec![DiagnosticCode::UnbalancedScope, DiagnosticCode::UnbalancedScope],
+        diagnostic_codes(".proc Outer\n.endscope\n")
+    );
+    assert_eq!(vec![DiagnosticCode::QualifiedDefinition], diagnostic_codes("Outer::inner = 1\n"));
+}
+
+#[test]
+fn struct_and_enum_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".struct Actor\nxpos .byte\nypos .byte\n.res 2\nvel .word ; two bytes\n.endstruct\n\
+             .enum Color\nRed\nGreen = 5\nBlue ; follows Green\n.endenum\n\
+             LDA Actor::vel,X\nLDX #.sizeof(Actor)\nLDA #Color::Blue\nLDY #Color::Red\n",
+        )
+        .unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        vec![0xB5, 0x04, 0xA2, 0x06, 0xA9, 0x06, 0xA0, 0x00],
+        output.bytes.unwrap()
+    );
+
+    assert_eq!(
+        vec![DiagnosticCode::DuplicateSymbol],
+        diagnostic_codes(".struct Point\nx .byte\nx .byte\n.endstruct\n")
+    );
+    assert_eq!(vec![DiagnosticCode::UnknownLabel], diagnostic_codes("LDA #.sizeof(Point)\n"));
+}
+
+#[test]
+fn builtin_functions_test() {
+    let mut assembler = Assembler::new();
+    assembler
+        .parse_unit(
+            "test.s",
+            ".org $8000\n.proc Init\nLDA #.lobyte($1234)\nLDA #.hibyte(table)\nLDA #.bankbyte($123456)\n\
+             LDA #.min(3, 1+1, 7)\nLDA #.max(3, 9)\nLDA #.strlen(\"hello\")\nLDA #.sizeof(Init)\n\
+             LDA #.defined(table) + .defined(missing)\n.endproc\ntable:\n.byte .sizeof(Init)\n",
+        )
+        .unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        vec![
+            0xA9, 0x34, 0xA9, 0x80, 0xA9, 0x12, 0xA9, 0x02, 0xA9, 0x09, 0xA9, 0x05, 0xA9, 0x10, 0xA9, 0x01, 0x10,
+        ],
+        output.bytes.unwrap()
+    );
+
+    assert_eq!(vec![DiagnosticCode::UnknownLabel], diagnostic_codes(".byte .sizeof(Missing)\n"));
+    assert_eq!(vec![DiagnosticCode::DivisionByZero], diagnostic_codes(".byte .max(1, 2 / 0)\n"));
+}
+
+fn cartridge_layout(rom_size: u32) -> MemoryLayout {
+    let mut layout = MemoryLayout::new();
+    layout.add_area(MemoryArea::new("ZP".into(), 0, 0x100, 0, false));
+    layout.add_area(MemoryArea::new("ROM".into(), (0x10000 - rom_size) as u16, rom_size, 0xFF, true));
+    layout.add_segment("ZEROPAGE", "ZP");
+    layout.add_segment("CODE", "ROM");
+    layout.add_segment("VECTORS", "ROM");
+    layout
+}
+
+fn layout_diagnostics(program: &str, layout: MemoryLayout) -> Vec<(DiagnosticCode, String)> {
+    let mut assembler = Assembler::new();
+    assembler.set_layout(layout).unwrap();
+    assembler.parse_unit("test.s", program).unwrap();
+    match *assembler.assemble().unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => diagnostics
+            .iter()
+            .map(|d| (d.code, d.message.clone()))
+            .collect(),
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+#[test]
+fn segment_layout_test() {
+    let program = ".segment \"VECTORS\"\n.vector reset\n.segment \"ZEROPAGE\"\ncounter: .res 1\n\
+                   .segment \"CODE\"\nreset:\nINC counter\nJMP reset\n";
+    let mut assembler = Assembler::new();
+    assembler.set_layout(cartridge_layout(16)).unwrap();
+    assembler.parse_unit("test.s", program).unwrap();
+    let output = assembler.assemble().unwrap();
+    assert_eq!(
+        vec![
+            0xEE, 0x00, 0x00, 0x4C, 0xF0, 0xFF, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        ],
+        output.bytes.unwrap()
+    );
+
+    assert_eq!(
+        vec![(
+            DiagnosticCode::AreaOverflow,
+            "area \"ROM\" is 4 byte(s), but its segments take 8 byte(s), which is 4 too many".to_string(),
+        )],
+        layout_diagnostics(program, cartridge_layout(4))
+    );
+    assert_eq!(
+        DiagnosticCode::UnknownSegment,
+        layout_diagnostics(".segment \"BSS\"\n", cartridge_layout(16))[0].0
+    );
+    assert_eq!(
+        DiagnosticCode::OrgWithLayout,
+        layout_diagnostics(".org $8000\nNOP\n", cartridge_layout(16))[0].0
+    );
+    assert_eq!(vec![DiagnosticCode::UnknownSegment], diagnostic_codes(".segment \"BSS\"\nNOP\n"));
+    assert!(MemoryLayout::from_json(r#"{"areas": [], "segments": [{"name": "CODE", "area": "ROM"}]}"#).is_err());
+}
+
+#[test]
+fn interleaved_areas_layout_test() {
+    // RODATA comes after BSS in the segment list, but still follows CODE in ROM rather than starting over
+    let mut layout = MemoryLayout::new();
+    layout.add_area(MemoryArea::new("ROM".into(), 0x8000, 10, 0xFF, true));
+    layout.add_area(MemoryArea::new("RAM".into(), 0x0200, 0x10, 0, false));
+    layout.add_segment("CODE", "ROM");
+    layout.add_segment("BSS", "RAM");
+    layout.add_segment("RODATA", "ROM");
+    let program = ".segment \"RODATA\"\ntable: .byte 1, 2\n.segment \"BSS\"\nbuffer: .res 2\n\
+                   .segment \"CODE\"\nLDA table\nSTA buffer\nRTS\n";
+    let mut assembler = Assembler::new();
+    assembler.set_layout(layout).unwrap();
+    assembler.parse_unit("test.s", program).unwrap();
+    assert_eq!(
+        vec![0xAD, 0x07, 0x80, 0x8D, 0x00, 0x02, 0x60, 0x01, 0x02, 0xFF],
+        assembler.assemble().unwrap().bytes.unwrap()
+    );
+}
+
+fn compile_object(unit_name: &str, program: &str) -> ObjectFile {
+    let mut assembler = Assembler::new();
+    assembler.parse_unit(unit_name, program).unwrap();
+    let json = assembler.compile().unwrap().to_json().unwrap();
+    ObjectFile::from_json(&json).unwrap()
+}
+
+#[test]
+fn object_link_test() {
+    let main = ".import helper\n.segment \"VECTORS\"\n.vector reset\n.segment \"CODE\"\nreset:\nJSR helper\n\
+                .set here = *\nJMP here\n";
+    let helper = ".export helper\n.segment \"ZEROPAGE\"\ncounter: .res 1\n.segment \"CODE\"\nhelper:\n\
+                  .set start = *\nINC counter\nBEQ start\nRTS\n";
+
+    let mut linker = Linker::new();
+    linker.set_layout(cartridge_layout(16)).unwrap();
+    linker.add_object(compile_object("main.s", main));
+    linker.add_object(compile_object("helper.s", helper));
+    let output = linker.link().unwrap();
+    assert_eq!(
+        vec![
+            0x20, 0xF6, 0xFF, 0x4C, 0xF3, 0xFF, 0xEE, 0x00, 0x00, 0xF0, 0xFB, 0x60, 0xF0, 0xFF, 0xFF, 0xFF,
+        ],
+        output.bytes.unwrap()
+    );
+    assert_eq!("helper.s", output.src_units.name(1));
+
+    // Segments are only checked against the layout when linking
+    let mut linker = Linker::new();
+    linker.add_object(compile_object("start.s", "NOP\n"));
+    linker.add_object(compile_object("helper.s", helper));
+    match *linker.link().unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            assert_eq!(DiagnosticCode::UnknownSegment, diagnostics[0].code);
+            assert_eq!(1, diagnostics[0].primary.unwrap().unit);
+        }
+        _ => panic!("expected diagnostics"),
+    }
+    let other_format = r#"{"format": "other", "version": 3, "units": [],
+                           "ir": {"blocks": [], "exports": [], "imports": [], "symbols": {}}}"#;
+    assert!(ObjectFile::from_json(other_format).is_err());
+}
+
+fn link_codes(objects: &[(&str, &str)]) -> Vec<DiagnosticCode> {
+    let mut linker = Linker::new();
+    for &(unit_name, program) in objects {
+        linker.add_object(compile_object(unit_name, program));
+    }
+    match *linker.link().unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => diagnostics.iter().map(|d| d.code).collect(),
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+#[test]
+fn export_import_test() {
+    let code = ".importzp counter\n.global main\nmain:\nloop:\nINC counter\nJMP loop\n";
+    let zero_page = ".segment \"ZEROPAGE\"\n.export counter\ncounter: .res 1\n.segment \"CODE\"\nloop:\nRTS\n";
+
+    // `loop` is private to each object, so defining it in both isn't a conflict
+    let mut linker = Linker::new();
+    linker.set_layout(cartridge_layout(8)).unwrap();
+    linker.add_object(compile_object("code.s", code));
+    linker.add_object(compile_object("zero_page.s", zero_page));
+    let output = linker.link().unwrap();
+    assert_eq!(vec![0xE6, 0x00, 0x4C, 0xF8, 0xFF, 0x60, 0xFF, 0xFF], output.bytes.unwrap());
+    assert!(output.symbols.unwrap().contains("; global\ncounter = $0000\nmain = $FFF8\n"));
+
+    // The missing import is only reported where it's imported, not again where it's used
+    assert_eq!(
+        vec![DiagnosticCode::UndefinedImport],
+        link_codes(&[("a.s", ".import helper\nJSR helper\nvalue = helper + 1\nLDA #<value\n")])
+    );
+    assert_eq!(
+        vec![DiagnosticCode::UnknownLabel],
+        link_codes(&[("a.s", "JSR helper\n"), ("b.s", "helper:\nRTS\n")])
+    );
+    assert_eq!(
+        vec![DiagnosticCode::DuplicateExport],
+        link_codes(&[("a.s", ".export f\nf:\nRTS\n"), ("b.s", ".export f\nf:\nRTS\n")])
+    );
+    assert_eq!(vec![DiagnosticCode::UndefinedExport], diagnostic_codes(".export f\nNOP\n"));
+    assert_eq!(vec![DiagnosticCode::DuplicateSymbol], diagnostic_codes(".import f\nf:\nNOP\n"));
+}
+
+#[test]
+fn dead_code_test() {
+    let main = ".global mul, div\n.segment \"VECTORS\"\n.vector reset\n.segment \"CODE\"\nreset:\nJSR mul\nJMP reset\n";
+    let lib = ".global mul, div, shared\n.proc mul\n.removable\nJSR shared\nRTS\n.endproc\n\
+               .proc div\n.removable\nRTS\n.endproc\n.proc shared\n.removable\nRTS\n.endproc\n";
+    let extra = ".removable\nextra:\nNOP\n";
+
+    let mut linker = Linker::new();
+    linker.set_layout(cartridge_layout(16)).unwrap();
+    linker.add_object(compile_object("main.s", main));
+    linker.add_object(compile_object("lib.s", lib));
+    linker.add_object(compile_object("extra.s", extra));
+    let output = linker.link().unwrap();
+    assert_eq!(
+        vec![
+            0x20, 0xF6, 0xFF, 0x4C, 0xF0, 0xFF, 0x20, 0xFA, 0xFF, 0x60, 0x60, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF,
+        ],
+        output.bytes.unwrap()
+    );
+    assert_eq!(
+        "proc div at lib.s:7, 1 byte(s)\nsegment \"CODE\" at extra.s:2, 1 byte(s)\n; removed 2 byte(s)\n",
+        output.removed.unwrap()
+    );
+
+    let mut linker = Linker::new();
+    linker.add_object(compile_object("lib.s", &format!(".org $8000\n{}", lib)));
+    linker.add_entry_point("div");
+    assert_eq!(
+        "proc mul at lib.s:3, 4 byte(s)\nproc shared at lib.s:12, 1 byte(s)\n; removed 5 byte(s)\n",
+        linker.link().unwrap().removed.unwrap()
+    );
+
+    assert_eq!(
+        vec![DiagnosticCode::MisplacedRemovable],
+        diagnostic_codes(".scope S\n.removable\n.endscope\n")
+    );
+}
+
+#[test]
+fn archive_test() {
+    let mut archive = Archive::new();
+    archive
+        .add_member("mul.o", compile_object("mul.s", ".export mul\nmul:\nJSR add\nRTS\n.import add\n"))
+        .unwrap();
+    archive
+        .add_member("add.o", compile_object("add.s", ".export add\nadd:\nCLC\nRTS\n"))
+        .unwrap();
+    archive
+        .add_member("div.o", compile_object("div.s", ".export div\ndiv:\nNOP\nRTS\n"))
+        .unwrap();
+    let duplicate = archive.add_member("other.o", compile_object("other.s", ".export add\nadd:\nRTS\n"));
+    match *duplicate.unwrap_err().kind() {
+        error::ErrorKind::InvalidArchive(_) => {}
+        ref kind => panic!("unexpected error: {:?}", kind),
+    }
+    let json = archive.to_json().unwrap();
+    assert!(Archive::is_archive(&json));
+    assert!(!Archive::is_archive(&compile_object("main.s", "NOP\n").to_json().unwrap()));
+
+    // Only the member that's imported and the member that it imports from are linked
+    let mut linker = Linker::new();
+    linker.add_object(compile_object("main.s", ".import mul\n.org $8000\nJSR mul\n"));
+    linker.add_archive(Archive::from_json(&json).unwrap());
+    let output = linker.link().unwrap();
+    assert_eq!(vec![0x20, 0x03, 0x80, 0x20, 0x07, 0x80, 0x60, 0x18, 0x60], output.bytes.unwrap());
+    assert_eq!("mul.s", output.src_units.name(1));
+    assert_eq!("add.s", output.src_units.name(2));
+    assert_eq!(3, output.src_units.units().len());
+}
+
+fn o65_layout(zero_page: u16, rom: u16, ram: u16) -> MemoryLayout {
+    let mut layout = MemoryLayout::new();
+    layout.add_area(MemoryArea::new("ZP".into(), zero_page, 0x10, 0, false));
+    layout.add_area(MemoryArea::new("ROM".into(), rom, 0x40, 0xFF, true));
+    layout.add_area(MemoryArea::new("RAM".into(), ram, 0x100, 0, false));
+    layout.add_segment("ZEROPAGE", "ZP");
+    layout.add_segment("CODE", "ROM");
+    layout.add_segment("DATA", "ROM");
+    layout.add_segment("BSS", "RAM");
+    layout
+}
+
+#[test]
+fn o65_test() {
+    let program = ".export start, table\n.segment \"ZEROPAGE\"\nptr: .res 2\n.segment \"BSS\"\nbuffer: .res 16\n\
+                   .segment \"DATA\"\ntable: .word start, buffer + 1\n.byte ptr, >table\n.segment \"CODE\"\nstart:\n\
+                   LDA #<table\nSTA ptr\nLDA #>table\nLDA buffer\nJMP start\n";
+    let mut assembler = Assembler::new();
+    assembler.set_layout(o65_layout(0x80, 0x1000, 0x2000)).unwrap();
+    assembler.set_output_format(OutputFormat::O65);
+    assembler.parse_unit("lib.s", program).unwrap();
+    let file = O65File::from_bytes(&assembler.assemble().unwrap().bytes.unwrap()).unwrap();
+    assert_eq!(
+        (0x1000, 0x100D, 0x2000, 16, 0x80, 2),
+        (file.text_base, file.data_base, file.bss_base, file.bss_length, file.zero_base, file.zero_length)
+    );
+    assert_eq!(vec![0xA9, 0x0D, 0x8D, 0x80, 0x00, 0xA9, 0x10, 0xAD, 0x00, 0x20, 0x4C, 0x00, 0x10], file.text);
+    assert_eq!(vec![0x00, 0x10, 0x01, 0x20, 0x80, 0x10], file.data);
+    assert_eq!(
+        vec![
+            O65Relocation::new(1, IRRelocationKind::Low, O65Segment::Data, 0),
+            O65Relocation::new(3, IRRelocationKind::Word, O65Segment::Zero, 0),
+            O65Relocation::new(6, IRRelocationKind::High(0x0D), O65Segment::Data, 0),
+            O65Relocation::new(8, IRRelocationKind::Word, O65Segment::Bss, 0),
+            O65Relocation::new(11, IRRelocationKind::Word, O65Segment::Text, 0),
+        ],
+        file.text_relocations
+    );
+    assert_eq!(
+        vec![
+            O65Export::new("start".into(), O65Segment::Text, 0x1000),
+            O65Export::new("table".into(), O65Segment::Data, 0x100D),
+        ],
+        file.exports
+    );
+
+    // Undefined names, both relocation tables, and the exports end the file
+    let bytes = file.to_bytes();
+    assert!(O65File::is_o65(&bytes));
+    assert!(bytes.ends_with(&[
+        0x00, 0x00, 0x02, 0x23, 0x02, 0x85, 0x03, 0x43, 0x0D, 0x02, 0x84, 0x03, 0x82, 0x00, 0x01, 0x82, 0x02, 0x84,
+        0x02, 0x25, 0x01, 0x43, 0x0D, 0x00, 0x02, 0x00, b's', b't', b'a', b'r', b't', 0x00, 0x02, 0x00, 0x10, b't',
+        b'a', b'b', b'l', b'e', 0x00, 0x03, 0x0D, 0x10,
+    ]));
+    assert_eq!(file, O65File::from_bytes(&bytes).unwrap());
+
+    // Linking the file back in moves each of its segments
+    let mut linker = Linker::new();
+    linker.set_layout(o65_layout(0x10, 0xC000, 0x0300)).unwrap();
+    linker.add_object(compile_object("main.s", ".import start, table\nJSR start\n.word table\n"));
+    linker.add_object(O65File::from_bytes(&bytes).unwrap().to_object("lib.o65").unwrap());
+    assert_eq!(
+        vec![
+            0x20, 0x05, 0xC0, 0x12, 0xC0, 0xA9, 0x12, 0x8D, 0x10, 0x00, 0xA9, 0xC0, 0xAD, 0x00, 0x03, 0x4C, 0x05, 0xC0,
+            0x05, 0xC0, 0x01, 0x03, 0x10, 0xC0, 0xFF,
+        ],
+        linker.link().unwrap().bytes.unwrap()[..25].to_vec()
+    );
+
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::O65);
+    assembler
+        .parse_unit("test.s", ".org $1000\nstart:\nend:\n.word end - start\n.word start * 2\n")
+        .unwrap();
+    match *assembler.assemble().unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            let codes: Vec<DiagnosticCode> = diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(vec![DiagnosticCode::NotRelocatable], codes);
+        }
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+#[test]
+fn o65_import_test() {
+    // Imports that no unit exports are left for the loader to supply
+    let program = ".import ext, value\n.org $1000\nJSR ext\nLDA #<value\n.word ext + 2\n";
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::O65);
+    assembler.parse_unit("main.s", program).unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    let file = O65File::from_bytes(&bytes).unwrap();
+    assert_eq!(vec!["ext".to_string(), "value".to_string()], file.undefined);
+    assert_eq!(vec![0x20, 0x00, 0x00, 0xA9, 0x00, 0x02, 0x00], file.text);
+    assert_eq!(
+        vec![
+            O65Relocation::new(1, IRRelocationKind::Word, O65Segment::Undefined, 0),
+            O65Relocation::new(4, IRRelocationKind::Low, O65Segment::Undefined, 1),
+            O65Relocation::new(5, IRRelocationKind::Word, O65Segment::Undefined, 0),
+        ],
+        file.text_relocations
+    );
+
+    // Linking the file imports the names from the units that export them
+    let mut linker = Linker::new();
+    linker.set_layout(o65_layout(0x10, 0xC000, 0x0300)).unwrap();
+    linker.add_object(file.to_object("main.o65").unwrap());
+    linker.add_object(compile_object("lib.s", ".export ext, value\next: RTS\nvalue = $40\n"));
+    assert_eq!(
+        vec![0x20, 0x07, 0xC0, 0xA9, 0x40, 0x09, 0xC0, 0x60],
+        linker.link().unwrap().bytes.unwrap()[..8].to_vec()
+    );
+
+    // A branch's offset can't reach an address that isn't known yet
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::O65);
+    assembler
+        .parse_unit("main.s", ".import ext\n.org $1000\nBNE ext\n")
+        .unwrap();
+    match *assembler.assemble().unwrap_err().kind() {
+        error::ErrorKind::Diagnostics(_, ref diagnostics) => {
+            let codes: Vec<DiagnosticCode> = diagnostics.iter().map(|d| d.code).collect();
+            assert_eq!(vec![DiagnosticCode::NotRelocatable], codes);
+        }
+        _ => panic!("expected diagnostics"),
+    }
+}
+
+fn format_output(program: &str, format: OutputFormat) -> String {
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(format);
+    assembler.parse_unit("test.s", program).unwrap();
+    String::from_utf8(assembler.assemble().unwrap().bytes.unwrap()).unwrap()
+}
+
+#[test]
+fn hex_formats_test() {
+    // The gap between the regions isn't filled, and the region that only reserves space isn't written
+    let program = ".org $0200\nbuffer: .res 4\n.org $8000\nLDA #1\n.org $8010\n\
+                   .byte 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18\n";
+    let segments = vec![
+        ImageSegment::new(0x8000, vec![0xA9, 0x01]),
+        ImageSegment::new(0x8010, (1..19).collect()),
+    ];
+
+    let hex = format_output(program, OutputFormat::IntelHex);
+    assert_eq!(
+        ":02800000A901D4\n:108010000102030405060708090A0B0C0D0E0F10D8\n:0280200011123B\n:00000001FF\n",
+        hex
+    );
+    assert_eq!(segments, format::ihex::read(&hex).unwrap());
+
+    let srec = format_output(program, OutputFormat::SRecord);
+    assert_eq!(
+        "S0030000FC\nS1058000A901D0\nS11380100102030405060708090A0B0C0D0E0F10D4\nS1058020111237\nS5030003F9\n\
+         S90380007C\n",
+        srec
+    );
+    assert_eq!(segments, format::srec::read(&srec).unwrap());
+
+    assert!(format::ihex::read(":02800000A901D5\n:00000001FF\n").is_err());
+    assert!(format::srec::read("S1058000A901D0\n").is_err());
+}
+
+fn nes_layout() -> MemoryLayout {
+    let mut layout = MemoryLayout::new();
+    layout.add_area(MemoryArea::new("PRG".into(), 0xC000, 0x4000, 0xFF, true));
+    layout.add_area(MemoryArea::new("PATTERNS".into(), 0x0000, 0x2000, 0, true));
+    layout.add_segment("CODE", "PRG");
+    layout.add_segment("CHR", "PATTERNS");
+    layout
+}
+
+#[test]
+fn ines_test() {
+    let program = ".inesprg 1\n.ineschr 1\n.inesmap 4\n.inesmir vertical\n.inesbat\n\
+                   .segment \"CODE\"\nreset: JMP reset\n.segment \"CHR\"\n.incbin \"tests/tile.chr\"\n";
+    let mut assembler = Assembler::new();
+    assembler.set_layout(nes_layout()).unwrap();
+    assembler.set_output_format(OutputFormat::Ines);
+    assembler.parse_unit("test.s", program).unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(16 + 0x4000 + 0x2000, bytes.len());
+    assert_eq!(
+        vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x43, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        bytes[..16].to_vec()
+    );
+    assert_eq!(vec![0x4C, 0x00, 0xC0, 0xFF], bytes[16..20].to_vec());
+    assert_eq!(include_bytes!("./tile.chr").to_vec(), bytes[16 + 0x4000..16 + 0x4010].to_vec());
+
+    // Options from the command line take the place of the program's, and NES 2.0 holds the larger mapper
+    let mut assembler = Assembler::new();
+    assembler.set_layout(nes_layout()).unwrap();
+    assembler.set_output_format(OutputFormat::Nes2);
+    assembler.set_ines_options(InesOptions {
+        mapper: Some(0x104),
+        ..InesOptions::default()
+    });
+    assembler.parse_unit("test.s", program).unwrap();
+    let bytes = assembler.assemble().unwrap().bytes.unwrap();
+    assert_eq!(
+        vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, 0x43, 0x08, 0x01, 0, 0x70, 0, 0, 0, 0, 0],
+        bytes[..16].to_vec()
+    );
+
+    let rom_error = |program: &str, layout: Option<MemoryLayout>| {
+        let mut assembler = Assembler::new();
+        if let Some(layout) = layout {
+            assembler.set_layout(layout).unwrap();
+        }
+        assembler.set_output_format(OutputFormat::Ines);
+        assembler.parse_unit("test.s", program).unwrap();
+        match *assembler.assemble().unwrap_err().kind() {
+            error::ErrorKind::InvalidRomImage(ref message) => message.clone(),
+            ref kind => panic!("expected a ROM image error, got {:?}", kind),
+        }
+    };
+    assert_eq!(
+        "PRG ROM has 1 banks, but the header gives 2",
+        rom_error(".inesprg 2\n.segment \"CODE\"\nNOP\n", Some(nes_layout()))
+    );
+    assert_eq!(
+        "PRG ROM is 1 bytes, which isn't a whole number of 16 KiB banks",
+        rom_error(".org $C000\nNOP\n", None)
+    );
+
+    assert_eq!(vec![DiagnosticCode::UnknownMirroring], diagnostic_codes(".inesmir diagonal\n"));
+}
+
+#[test]
+fn prg_test() {
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::Prg);
+    assembler
+        .parse_unit("test.s", ".org $0801\n.basicstub start\nstart: INC $D020\nJMP start\n")
+        .unwrap();
+    assert_eq!(
+        vec![
+            0x01, 0x08, 0x0B, 0x08, 0x0A, 0x00, 0x9E, 0x32, 0x30, 0x36, 0x32, 0x00, 0x00, 0x00, 0x00, 0xEE, 0x20, 0xD0,
+            0x4C, 0x0E, 0x08,
+        ],
+        assembler.assemble().unwrap().bytes.unwrap()
+    );
+
+    // The line links to the end of the program, whatever the number of digits
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::Prg);
+    assembler
+        .parse_unit("test.s", ".org $0200\n.basicstub start\nstart: RTS\n")
+        .unwrap();
+    assert_eq!(
+        vec![0x00, 0x02, 0x09, 0x02, 0x0A, 0x00, 0x9E, 0x35, 0x32, 0x35, 0x00, 0x00, 0x00, 0x00, 0x00, 0x60],
+        assembler.assemble().unwrap().bytes.unwrap()
+    );
+
+    assert_eq!(vec![DiagnosticCode::UnknownLabel], diagnostic_codes(".basicstub nowhere\n"));
+}
+
+#[test]
+fn xex_test() {
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::Xex);
+    assembler
+        .parse_unit(
+            "test.s",
+            ".run main\n.org $2000\nmain: JMP main\n.org $3000\ninit: RTS\n.init init\n.byte 1\n\
+             .org $4000\n.byte 2\n",
+        )
+        .unwrap();
+    // The init vector is loaded right after the code that it follows, before the rest of the file
+    assert_eq!(
+        vec![
+            0xFF, 0xFF, 0x00, 0x20, 0x02, 0x20, 0x4C, 0x00, 0x20, 0x00, 0x30, 0x01, 0x30, 0x60, 0x01, 0xE2, 0x02, 0xE3,
+            0x02, 0x00, 0x30, 0x00, 0x40, 0x00, 0x40, 0x02, 0xE0, 0x02, 0xE1, 0x02, 0x00, 0x20,
+        ],
+        assembler.assemble().unwrap().bytes.unwrap()
+    );
+
+    // The run address can name a label in another object
+    let mut linker = Linker::new();
+    linker.set_output_format(OutputFormat::Xex);
+    linker.add_object(compile_object("start.s", ".import main\n.run main\n"));
+    linker.add_object(compile_object("main.s", ".export main\n.org $2000\nmain: JMP main\n"));
+    assert_eq!(
+        vec![0xFF, 0xFF, 0x00, 0x20, 0x02, 0x20, 0x4C, 0x00, 0x20, 0xE0, 0x02, 0xE1, 0x02, 0x00, 0x20],
+        linker.link().unwrap().bytes.unwrap()
+    );
+
+    // A segment can run up to the top of memory
+    let mut assembler = Assembler::new();
+    assembler.set_output_format(OutputFormat::Xex);
+    assembler.parse_unit("test.s", ".org $FFFE\n.word $1234\n").unwrap();
+    assert_eq!(
+        vec![0xFF, 0xFF, 0xFE, 0xFF, 0xFF, 0xFF, 0x34, 0x12],
+        assembler.assemble().unwrap().bytes.unwrap()
+    );
+
+    assert_eq!(vec![DiagnosticCode::NumberOutOfRange], diagnostic_codes(".run -1\n"));
+}
@@ -29,7 +29,7 @@ halt:
 ; Program to copy that expects to be run from $A000
 ;
 copy_location:
-.org $A000
+.rorg $A000
 
 program_to_copy:
     LDX #3
@@ -37,6 +37,7 @@ pointless_loop:
     DEX
     BNE pointless_loop
     JMP program_to_copy
+.endrorg
 
 ;
 ; Vectors